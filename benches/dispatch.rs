@@ -0,0 +1,64 @@
+//! Times the dispatch loop on the programs in `benches/programs`.
+//!
+//! Each program is run several times through the release binary and the
+//! fastest run is reported. The time is the one the interpreter prints for
+//! `run`, so parsing, compiling and verifying are not counted.
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+
+const RUNS: usize = 5;
+
+fn main() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/programs");
+    let mut programs: Vec<_> = fs::read_dir(&dir)
+        .expect("benches/programs is missing")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "bo"))
+        .collect();
+    programs.sort();
+    println!(
+        "{:<12}{:>14}{:>10}{:>12}",
+        "program", "instructions", "ms", "ns/instr"
+    );
+    for program in programs {
+        let name = program.file_stem().unwrap().to_string_lossy().into_owned();
+        match time(&program) {
+            Ok((instructions, ms)) => println!(
+                "{name:<12}{instructions:>14}{ms:>10}{:>12.2}",
+                ms as f64 * 1e6 / instructions as f64
+            ),
+            Err(e) => println!("{name:<12}{e:>36}"),
+        }
+    }
+}
+
+/// Returns the instruction count and the fastest of `RUNS` run times.
+fn time(program: &Path) -> Result<(u64, u64), String> {
+    let mut best: Option<(u64, u64)> = None;
+    for _ in 0..RUNS {
+        let output = Command::new(env!("CARGO_BIN_EXE_bytecodeinterpreter"))
+            .arg(program)
+            .output()
+            .map_err(|e| e.to_string())?;
+        if !output.status.success() {
+            return Err("failed".to_string());
+        }
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let run = parse_timing(&stdout).ok_or("no timing in output")?;
+        if best.is_none_or(|(_, ms)| run.1 < ms) {
+            best = Some(run);
+        }
+    }
+    Ok(best.unwrap())
+}
+
+/// Reads `Executed <n> instructions in: <ms>ms` from the interpreter output.
+fn parse_timing(stdout: &str) -> Option<(u64, u64)> {
+    let line = stdout.lines().find(|l| l.starts_with("Executed "))?;
+    let mut words = line.split_whitespace();
+    let instructions = words.nth(1)?.parse().ok()?;
+    let ms = words.last()?.strip_suffix("ms")?.parse().ok()?;
+    Some((instructions, ms))
+}
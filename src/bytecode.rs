@@ -0,0 +1,478 @@
+use crate::chunk::Chunk;
+use crate::parser::Literal;
+use crate::tokenizer::Span;
+use crate::value::{FunctionSignature, Type};
+use ordered_float::OrderedFloat;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{Read, Write};
+use std::rc::Rc;
+
+// Layout of a .bbc file, all integers little endian:
+//
+//   magic "BBC\0", version u16
+//   main: u8 present flag, then u32 function index and its type
+//   string table: u32 count, then strings
+//   signatures: u32 count, then name, u32 parameter count, parameter types,
+//               return type and a u8 native flag
+//   constants: u32 count, then a u8 tag and payload per literal
+//   chunks: u32 count, then u32 code length, code, u32 position count and
+//           per position a u32 offset, u8 present flag and, if present,
+//           u32 file string, u32 line, u32 column, u32 source string
+//
+// Strings are a u32 byte length followed by UTF-8. Types are a u8 tag, where
+// arrays are followed by their element type and functions by a u32 parameter
+// count, the parameter types and the return type.
+
+pub const MAGIC: &[u8; 4] = b"BBC\0";
+pub const VERSION: u16 = 3;
+
+const TYPE_FLOAT: u8 = 0;
+const TYPE_INT: u8 = 1;
+const TYPE_BOOL: u8 = 2;
+const TYPE_STRING: u8 = 3;
+const TYPE_ARRAY: u8 = 4;
+const TYPE_ANY: u8 = 5;
+const TYPE_FUNCTION: u8 = 6;
+
+/// How deeply array and function types may nest, so a crafted file cannot
+/// exhaust the stack while its types are read.
+const MAX_TYPE_DEPTH: usize = 256;
+
+const LIT_INTEGER: u8 = 0;
+const LIT_FLOAT: u8 = 1;
+const LIT_BOOL: u8 = 2;
+const LIT_STRING: u8 = 3;
+
+#[derive(Debug)]
+pub enum BytecodeError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u16),
+    Malformed(String),
+}
+
+impl fmt::Display for BytecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BytecodeError::Io(e) => write!(f, "Could not read bytecode:\n\t{e}"),
+            BytecodeError::BadMagic => write!(f, "Not a Breakout bytecode file"),
+            BytecodeError::UnsupportedVersion(v) => {
+                write!(f, "Unsupported bytecode version {v}, expected {VERSION}")
+            }
+            BytecodeError::Malformed(message) => write!(f, "Malformed bytecode:\n\t{message}"),
+        }
+    }
+}
+impl Error for BytecodeError {}
+
+impl From<std::io::Error> for BytecodeError {
+    fn from(e: std::io::Error) -> Self {
+        BytecodeError::Io(e)
+    }
+}
+
+/// Everything `compiler::compile` produces, in a form that can be written to
+/// and loaded from a .bbc file.
+pub struct BytecodeProgram {
+    pub chunks: Vec<Chunk>,
+    pub constants: Vec<Literal>,
+    pub function_signatures: Vec<FunctionSignature>,
+    pub main: Option<(usize, Type)>,
+}
+
+impl BytecodeProgram {
+    pub fn write(&self, out: &mut impl Write) -> Result<(), BytecodeError> {
+        let mut strings = StringTable::default();
+        let mut body = Vec::new();
+
+        body.extend_from_slice(&(self.function_signatures.len() as u32).to_le_bytes());
+        for signature in &self.function_signatures {
+            write_str(&mut body, &signature.name);
+            body.extend_from_slice(&(signature.params.len() as u32).to_le_bytes());
+            for t in &signature.params {
+                write_type(&mut body, t);
+            }
+            write_type(&mut body, &signature.ret);
+            body.push(signature.native as u8);
+        }
+
+        body.extend_from_slice(&(self.constants.len() as u32).to_le_bytes());
+        for constant in &self.constants {
+            match constant {
+                Literal::Integer(i) => {
+                    body.push(LIT_INTEGER);
+                    body.extend_from_slice(&i.to_le_bytes());
+                }
+                Literal::Float(f) => {
+                    body.push(LIT_FLOAT);
+                    body.extend_from_slice(&f.0.to_le_bytes());
+                }
+                Literal::Bool(b) => {
+                    body.push(LIT_BOOL);
+                    body.push(*b as u8);
+                }
+                Literal::String(s) => {
+                    body.push(LIT_STRING);
+                    write_str(&mut body, s);
+                }
+            }
+        }
+
+        body.extend_from_slice(&(self.chunks.len() as u32).to_le_bytes());
+        for chunk in &self.chunks {
+            let code = chunk.as_bytes();
+            body.extend_from_slice(&(code.len() as u32).to_le_bytes());
+            body.extend_from_slice(code);
+            let positions = chunk.get_positions();
+            body.extend_from_slice(&(positions.len() as u32).to_le_bytes());
+            for (offset, span) in positions {
+                body.extend_from_slice(&(*offset as u32).to_le_bytes());
+                match span {
+                    Some(span) => {
+                        body.push(1);
+                        body.extend_from_slice(&strings.intern(&span.file).to_le_bytes());
+                        body.extend_from_slice(&(span.line as u32).to_le_bytes());
+                        body.extend_from_slice(&(span.column as u32).to_le_bytes());
+                        body.extend_from_slice(&strings.intern(&span.source).to_le_bytes());
+                    }
+                    None => body.push(0),
+                }
+            }
+        }
+
+        let mut header = Vec::new();
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        match &self.main {
+            Some((index, t)) => {
+                header.push(1);
+                header.extend_from_slice(&(*index as u32).to_le_bytes());
+                write_type(&mut header, t);
+            }
+            None => header.push(0),
+        }
+        header.extend_from_slice(&(strings.strings.len() as u32).to_le_bytes());
+        for s in &strings.strings {
+            write_str(&mut header, s);
+        }
+
+        out.write_all(&header)?;
+        out.write_all(&body)?;
+        Ok(())
+    }
+
+    pub fn read(input: &mut impl Read) -> Result<BytecodeProgram, BytecodeError> {
+        let mut bytes = Vec::new();
+        input.read_to_end(&mut bytes)?;
+        let mut reader = Reader {
+            bytes: &bytes,
+            pos: 0,
+        };
+
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(BytecodeError::BadMagic);
+        }
+        let version = u16::from_le_bytes(reader.take(2)?.try_into().unwrap());
+        if version != VERSION {
+            return Err(BytecodeError::UnsupportedVersion(version));
+        }
+
+        let main = match reader.u8()? {
+            0 => None,
+            _ => Some((reader.u32()? as usize, reader.read_type()?)),
+        };
+
+        let mut strings: Vec<Rc<str>> = Vec::new();
+        for _ in 0..reader.u32()? {
+            strings.push(reader.string()?.into());
+        }
+        let string_at = |i: u32| {
+            strings
+                .get(i as usize)
+                .cloned()
+                .ok_or_else(|| BytecodeError::Malformed(format!("String {i} out of range")))
+        };
+
+        let mut function_signatures = Vec::new();
+        for _ in 0..reader.u32()? {
+            let name = reader.string()?;
+            let mut params = Vec::new();
+            for _ in 0..reader.u32()? {
+                params.push(reader.read_type()?);
+            }
+            let ret = reader.read_type()?;
+            function_signatures.push(FunctionSignature {
+                name,
+                params,
+                ret,
+                native: reader.u8()? != 0,
+            });
+        }
+
+        let mut constants = Vec::new();
+        for _ in 0..reader.u32()? {
+            let constant = match reader.u8()? {
+                LIT_INTEGER => {
+                    Literal::Integer(i64::from_le_bytes(reader.take(8)?.try_into().unwrap()))
+                }
+                LIT_FLOAT => Literal::Float(OrderedFloat(f64::from_le_bytes(
+                    reader.take(8)?.try_into().unwrap(),
+                ))),
+                LIT_BOOL => Literal::Bool(reader.u8()? != 0),
+                LIT_STRING => Literal::String(reader.string()?),
+                tag => {
+                    return Err(BytecodeError::Malformed(format!(
+                        "Unknown constant tag {tag}"
+                    )));
+                }
+            };
+            constants.push(constant);
+        }
+
+        let mut chunks = Vec::new();
+        for _ in 0..reader.u32()? {
+            let len = reader.u32()? as usize;
+            let code = reader.take(len)?;
+            let mut positions = Vec::new();
+            for _ in 0..reader.u32()? {
+                let offset = reader.u32()? as usize;
+                let span = match reader.u8()? {
+                    0 => None,
+                    _ => Some(Span {
+                        file: string_at(reader.u32()?)?,
+                        line: reader.u32()? as usize,
+                        column: reader.u32()? as usize,
+                        source: string_at(reader.u32()?)?,
+                    }),
+                };
+                positions.push((offset, span));
+            }
+            chunks.push(Chunk::from_parts(code, positions));
+        }
+
+        if reader.pos != bytes.len() {
+            return Err(BytecodeError::Malformed("Trailing bytes".to_string()));
+        }
+        // every function but the natives has a chunk, in order
+        let functions = function_signatures.iter().filter(|f| !f.native).count();
+        if chunks.len() != functions {
+            return Err(BytecodeError::Malformed(format!(
+                "{} chunks for {functions} functions",
+                chunks.len()
+            )));
+        }
+        if let Some((index, t)) = &main {
+            match function_signatures.get(*index) {
+                Some(signature) if !signature.native && signature.ret == *t => {}
+                Some(_) => {
+                    return Err(BytecodeError::Malformed(format!(
+                        "Main function {index} does not return {t}"
+                    )));
+                }
+                None => {
+                    return Err(BytecodeError::Malformed(format!(
+                        "Main function {index} out of range"
+                    )));
+                }
+            }
+        }
+        Ok(BytecodeProgram {
+            chunks,
+            constants,
+            function_signatures,
+            main,
+        })
+    }
+}
+
+#[derive(Default)]
+struct StringTable {
+    strings: Vec<Rc<str>>,
+    indices: HashMap<Rc<str>, u32>,
+}
+
+impl StringTable {
+    fn intern(&mut self, s: &Rc<str>) -> u32 {
+        if let Some(i) = self.indices.get(s) {
+            return *i;
+        }
+        let i = self.strings.len() as u32;
+        self.strings.push(s.clone());
+        self.indices.insert(s.clone(), i);
+        i
+    }
+}
+
+fn write_str(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(&(s.len() as u32).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+}
+
+fn write_type(out: &mut Vec<u8>, t: &Type) {
+    match t {
+        Type::Float => out.push(TYPE_FLOAT),
+        Type::Int => out.push(TYPE_INT),
+        Type::Bool => out.push(TYPE_BOOL),
+        Type::String => out.push(TYPE_STRING),
+        Type::Array(inner) => {
+            out.push(TYPE_ARRAY);
+            write_type(out, inner);
+        }
+        Type::Function(params, ret) => {
+            out.push(TYPE_FUNCTION);
+            out.extend_from_slice(&(params.len() as u32).to_le_bytes());
+            for t in params {
+                write_type(out, t);
+            }
+            write_type(out, ret);
+        }
+        Type::AnyType => out.push(TYPE_ANY),
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], BytecodeError> {
+        let end = self
+            .pos
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or_else(|| BytecodeError::Malformed("Unexpected end of file".to_string()))?;
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+    fn u8(&mut self) -> Result<u8, BytecodeError> {
+        Ok(self.take(1)?[0])
+    }
+    fn u32(&mut self) -> Result<u32, BytecodeError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+    fn string(&mut self) -> Result<String, BytecodeError> {
+        let len = self.u32()? as usize;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| BytecodeError::Malformed("Invalid UTF-8 in string".to_string()))
+    }
+    fn read_type(&mut self) -> Result<Type, BytecodeError> {
+        self.read_nested_type(0)
+    }
+    fn read_nested_type(&mut self, depth: usize) -> Result<Type, BytecodeError> {
+        if depth > MAX_TYPE_DEPTH {
+            return Err(BytecodeError::Malformed(
+                "Type nested too deeply".to_string(),
+            ));
+        }
+        match self.u8()? {
+            TYPE_FLOAT => Ok(Type::Float),
+            TYPE_INT => Ok(Type::Int),
+            TYPE_BOOL => Ok(Type::Bool),
+            TYPE_STRING => Ok(Type::String),
+            TYPE_ARRAY => Ok(Type::Array(Box::new(self.read_nested_type(depth + 1)?))),
+            TYPE_ANY => Ok(Type::AnyType),
+            TYPE_FUNCTION => {
+                let mut params = Vec::new();
+                for _ in 0..self.u32()? {
+                    params.push(self.read_nested_type(depth + 1)?);
+                }
+                let ret = self.read_nested_type(depth + 1)?;
+                Ok(Type::Function(params, Box::new(ret)))
+            }
+            tag => Err(BytecodeError::Malformed(format!("Unknown type tag {tag}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::compile;
+    use crate::parser::parse_line;
+    use crate::prelude;
+    use crate::value::BreakoutValue;
+    use crate::vm::VM;
+
+    fn compile_source(source: &str) -> BytecodeProgram {
+        let file: Rc<str> = "<source>".into();
+        let mut tokens = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            parse_line(line, &file, i + 1, &mut tokens).unwrap();
+        }
+        tokens.reverse();
+        let mut function_signatures = prelude::signatures();
+        let mut constants = Vec::new();
+        let (chunks, main, main_type) =
+            compile(&mut tokens, &mut function_signatures, &mut constants).unwrap();
+        BytecodeProgram {
+            chunks,
+            constants,
+            function_signatures,
+            main: main.zip(main_type),
+        }
+    }
+
+    fn to_bytes(program: &BytecodeProgram) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        program.write(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = "[string] words := s : string => split s \" \"
+string main := ++ @ words \"x yz\" 1 toString fabs - 0.0 1.5";
+        let bytes = to_bytes(&compile_source(source));
+        let program = BytecodeProgram::read(&mut bytes.as_slice()).unwrap();
+        assert_eq!(to_bytes(&program), bytes);
+
+        let mut vm = VM::new();
+        let mut chunks = program.chunks.into_iter();
+        for signature in &program.function_signatures {
+            match signature.native {
+                true => vm.give_native(prelude::find(signature).unwrap()),
+                false => vm.give_data(chunks.next().unwrap()),
+            }
+        }
+        vm.update_constants(&program.constants);
+        vm.update_function_signatures(&program.function_signatures);
+        vm.set_main(program.main.unwrap().0);
+        vm.verify().unwrap();
+        assert_eq!(
+            vm.run().unwrap(),
+            BreakoutValue::String("yz1.5".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let read = |bytes: &[u8]| BytecodeProgram::read(&mut &bytes[..]).err().unwrap();
+        let bytes = to_bytes(&compile_source("int main := + 1 2"));
+        assert!(matches!(read(b"BBC"), BytecodeError::BadMagic));
+        assert!(matches!(read(b"ELF\0\x03\0"), BytecodeError::BadMagic));
+        assert!(matches!(
+            read(b"BBC\0\x09\0"),
+            BytecodeError::UnsupportedVersion(9)
+        ));
+        for len in 6..bytes.len() {
+            assert!(matches!(read(&bytes[..len]), BytecodeError::Malformed(_)));
+        }
+
+        // main is function 1 of the header, past the magic, version and flag
+        let mut wrong_main = bytes.clone();
+        wrong_main[7..11].copy_from_slice(&10_000u32.to_le_bytes());
+        assert!(matches!(read(&wrong_main), BytecodeError::Malformed(_)));
+        let mut wrong_type = bytes.clone();
+        wrong_type[11] = TYPE_STRING;
+        assert!(matches!(read(&wrong_type), BytecodeError::Malformed(_)));
+
+        // a main type of arrays nested far too deeply
+        let mut deep = bytes[..11].to_vec();
+        deep.extend(std::iter::repeat_n(TYPE_ARRAY, 100_000));
+        assert!(matches!(read(&deep), BytecodeError::Malformed(_)));
+    }
+}
@@ -1,6 +1,10 @@
 use std::fmt::Debug;
+use std::fmt::Write;
 
-use crate::common::OpCode;
+use crate::common::{OpCode, Operands};
+use crate::parser::Literal;
+use crate::tokenizer::Span;
+use crate::value::FunctionSignature;
 
 #[derive(Copy, Clone)]
 pub union ChunkData {
@@ -9,7 +13,6 @@ pub union ChunkData {
 }
 impl Debug for ChunkData {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", unsafe { OpCode::from(self.data) })?;
         write!(f, "{:?}", unsafe { self.data })
     }
 }
@@ -18,59 +21,250 @@ impl Debug for ChunkData {
 pub struct Chunk {
     data: Vec<ChunkData>,
     pointer: usize,
+    /// Offset of every opcode and the source position it was compiled from.
+    positions: Vec<(usize, Option<Span>)>,
 }
 
 impl Chunk {
     pub fn new(data: Vec<ChunkData>) -> Chunk {
-        Chunk { data, pointer: 0 }
+        Chunk {
+            data,
+            pointer: 0,
+            positions: Vec::new(),
+        }
+    }
+    /// Rebuilds a chunk from its raw bytes and position table.
+    pub fn from_parts(bytes: &[u8], positions: Vec<(usize, Option<Span>)>) -> Chunk {
+        Chunk {
+            data: bytes.iter().map(|&data| ChunkData { data }).collect(),
+            pointer: 0,
+            positions,
+        }
+    }
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.data.as_ptr() as *const u8, self.data.len()) }
+    }
+    pub fn get_positions(&self) -> &[(usize, Option<Span>)] {
+        &self.positions
     }
     pub fn add_opcode(&mut self, opcode: OpCode) {
+        self.positions.push((self.data.len(), None));
         self.data.push(ChunkData { opcode });
     }
     pub fn add_byte(&mut self, data: u8) {
         self.data.push(ChunkData { data });
     }
-    pub fn get_length(&mut self) -> usize {
+    /// Adds `opcode` followed by `operands`, each encoded little endian in the
+    /// width the opcode table gives it.
+    pub fn add_instruction(&mut self, opcode: OpCode, operands: &[usize]) {
+        self.add_opcode(opcode);
+        for (operand, width) in operands.iter().zip(opcode.info().operand_widths) {
+            for byte in &operand.to_le_bytes()[..*width] {
+                self.add_byte(*byte);
+            }
+        }
+    }
+    pub fn get_length(&self) -> usize {
         self.data.len()
     }
     pub fn add_chunk(&mut self, other: &mut Chunk) {
+        let offset = self.data.len();
+        self.positions.extend(
+            other
+                .positions
+                .drain(..)
+                .map(|(position, span)| (position + offset, span)),
+        );
         self.data.append(other.data.as_mut());
     }
+    /// Attributes every instruction from `start` onwards that has no position yet to `span`.
+    pub fn mark_positions(&mut self, start: usize, span: &Option<Span>) {
+        for (position, item) in self.positions.iter_mut().rev() {
+            if *position < start {
+                break;
+            }
+            if item.is_none() {
+                item.clone_from(span);
+            }
+        }
+    }
+    /// Source position of the instruction containing `offset`.
+    pub fn get_position(&self, offset: usize) -> Option<&Span> {
+        let i = self
+            .positions
+            .partition_point(|(position, _)| *position <= offset);
+        self.positions.get(i.checked_sub(1)?)?.1.as_ref()
+    }
     #[inline]
     pub fn set_pointer(&mut self, pointer: usize) {
         self.pointer = pointer;
     }
     #[inline]
-    pub fn get_pointer(&mut self) -> usize {
+    pub fn get_pointer(&self) -> usize {
         self.pointer
     }
     #[inline]
-    fn get_data(&mut self, size: usize) -> &[u8] {
-        let byte_slice = unsafe {
-            std::slice::from_raw_parts(
-                self.data[self.pointer..self.pointer + size].as_ptr() as *const u8,
-                size,
-            )
-        };
-        self.pointer += size;
-        byte_slice
+    pub fn get_byte(&self, offset: usize) -> Option<u8> {
+        self.data.get(offset).map(|d| unsafe { d.data })
     }
+    /// Decodes the instruction at `offset` without moving the pointer into its
+    /// opcode, operands and length in bytes, or `None` if the chunk ends
+    /// before the opcode or one of its operands.
     #[inline]
-    pub fn get_instruction(&mut self) -> (OpCode, &[u8]) {
-        let oc: OpCode = unsafe { self.data[self.pointer].opcode };
-        self.pointer += 1;
-        match oc {
-            OpCode::FunctionCall
-            | OpCode::ConstructArray
-            | OpCode::DropLocalArr
-            | OpCode::StackLoadLocalVarArr => (oc, self.get_data(2)),
-            OpCode::Constant
-            | OpCode::StackLoadLocalVar
-            | OpCode::StackLoadLocalVarStr
-            | OpCode::DropLocalStr
-            | OpCode::Advance
-            | OpCode::AdvanceIfFalse => (oc, self.get_data(1)),
-            _ => (oc, &[]),
+    pub fn decode(&self, offset: usize) -> Option<(OpCode, Operands, usize)> {
+        let bytes = self.as_bytes();
+        let oc = OpCode::from(*bytes.get(offset)?);
+        let info = oc.info();
+        let size = info.operands_size();
+        let operands = bytes.get(offset + 1..offset + 1 + size)?;
+        Some((oc, info.decode_operands(operands), 1 + size))
+    }
+    /// Replaces the slot of every local variable load with `slot` of it. The
+    /// new slots must fit the width each load was emitted with.
+    pub fn map_local_slots(&mut self, mut slot: impl FnMut(usize) -> usize) {
+        let mut offset = 0;
+        while let Some((opcode, operands, len)) = self.decode(offset) {
+            if let OpCode::StackLoadLocalVar | OpCode::StackLoadLocalVarLong = opcode {
+                let bytes = slot(operands[0]).to_le_bytes();
+                for (data, byte) in self.data[offset + 1..offset + len].iter_mut().zip(bytes) {
+                    *data = ChunkData { data: byte };
+                }
+            }
+            offset += len;
+        }
+    }
+    /// Decodes the instruction at the pointer and moves past it.
+    #[inline]
+    pub fn get_instruction(&mut self) -> Option<(OpCode, Operands)> {
+        let (oc, operands, len) = self.decode(self.pointer)?;
+        self.pointer += len;
+        Some((oc, operands))
+    }
+
+    /// Renders one instruction per line with its offset, source line, mnemonic
+    /// and decoded operands.
+    pub fn disassemble(
+        &self,
+        name: &str,
+        constants: &[Literal],
+        function_signatures: &[FunctionSignature],
+    ) -> String {
+        let mut out = String::new();
+        writeln!(out, "== {name} ==").unwrap();
+        let mut offset = 0;
+        let mut last_line = None;
+        while offset < self.data.len() {
+            let line = self
+                .get_position(offset)
+                .map(|span| (span.file.clone(), span.line));
+            let line_column = if line.is_some() && line == last_line {
+                "   |".to_string()
+            } else {
+                line.as_ref()
+                    .map_or("    ".to_string(), |(_, n)| format!("{n:4}"))
+            };
+            last_line = line;
+            let Some((oc, operands, len)) = self.decode(offset) else {
+                writeln!(out, "{offset:04} {line_column}  <truncated>").unwrap();
+                break;
+            };
+            let next = offset + len;
+            let decoded = match oc {
+                OpCode::Constant | OpCode::ConstantLong => {
+                    let index = operands[0];
+                    match constants.get(index) {
+                        Some(Literal::String(s)) => format!("{index} ({s:?})"),
+                        Some(literal) => format!("{index} ({literal})"),
+                        None => format!("{index} (<missing>)"),
+                    }
+                }
+                OpCode::PushFunction | OpCode::PushFunctionLong => {
+                    let index = operands[0];
+                    let name = function_signatures
+                        .get(index)
+                        .map_or("<missing>", |f| f.name.as_str());
+                    format!("{index} ({name})")
+                }
+                OpCode::CallIndirect
+                | OpCode::CallIndirectLong
+                | OpCode::TailCallIndirect
+                | OpCode::TailCallIndirectLong => format!("args {}", operands[0]),
+                OpCode::MakeClosure | OpCode::MakeClosureLong => {
+                    let index = operands[0];
+                    let name = function_signatures
+                        .get(index)
+                        .map_or("<missing>", |f| f.name.as_str());
+                    format!("{index} ({name}) captures {}", operands[1])
+                }
+                OpCode::FunctionCall
+                | OpCode::FunctionCallLong
+                | OpCode::TailCall
+                | OpCode::TailCallLong
+                | OpCode::NativeCall
+                | OpCode::NativeCallLong => {
+                    let index = operands[0];
+                    let name = function_signatures
+                        .get(index)
+                        .map_or("<missing>", |f| f.name.as_str());
+                    format!("{index} ({name}) args {}", operands[1])
+                }
+                OpCode::Advance
+                | OpCode::AdvanceIfFalse
+                | OpCode::AdvanceLong
+                | OpCode::AdvanceIfFalseLong => {
+                    format!("{} -> {:04}", operands[0], next + operands[0])
+                }
+                OpCode::ConstructArray | OpCode::ConstructArrayLong => {
+                    format!("size {}", operands[0])
+                }
+                OpCode::StackLoadLocalVar | OpCode::StackLoadLocalVarLong => {
+                    format!("slot {}", operands[0])
+                }
+                _ => operands[..oc.info().operand_widths.len()]
+                    .iter()
+                    .map(|operand| operand.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            };
+            let mnemonic = oc.info().mnemonic;
+            let text = format!("{offset:04} {line_column}  {mnemonic:<22}{decoded}");
+            writeln!(out, "{}", text.trim_end()).unwrap();
+            offset = next;
         }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Type;
+
+    #[test]
+    fn disassemble() {
+        let mut chunk = Chunk::new(Vec::new());
+        chunk.add_opcode(OpCode::True);
+        chunk.add_instruction(OpCode::AdvanceIfFalse, &[4]);
+        chunk.add_instruction(OpCode::Constant, &[0]);
+        chunk.add_instruction(OpCode::Advance, &[3]);
+        chunk.add_instruction(OpCode::FunctionCall, &[0, 0]);
+        chunk.add_opcode(OpCode::Return);
+        let constants = [Literal::String("hi".to_string())];
+        let signatures = [FunctionSignature::new(
+            "greet".to_string(),
+            Vec::new(),
+            Type::String,
+        )];
+        assert_eq!(
+            chunk.disassemble("main", &constants, &signatures),
+            "== main ==
+0000       True
+0001       AdvanceIfFalse        4 -> 0007
+0003       Constant              0 (\"hi\")
+0005       Advance               3 -> 0010
+0007       FunctionCall          0 (greet) args 0
+0010       Return
+"
+        );
     }
 }
@@ -1,124 +1,244 @@
-#[repr(u8)]
+/// How an instruction changes the depth of the value stack.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum OpCode {
-    Return,
-    Constant,
-
-    AddI,
-    SubtractI,
-    MultiplyI,
-    DivideI,
-
-    AddF,
-    SubtractF,
-    MultiplyF,
-    DivideF,
-
-    True,
-    False,
-
-    EqualI,
-    EqualF,
-    EqualS,
-    EqualB,
-
-    GreaterThanI,
-    LessThanI,
-    GreaterThanOrEqualI,
-    LessThanOrEqualI,
-
-    GreaterThanF,
-    LessThanF,
-    GreaterThanOrEqualF,
-    LessThanOrEqualF,
-
-    Advance,
-    AdvanceIfFalse,
-
-    Not,
+pub enum StackEffect {
+    /// Pops and then pushes a fixed number of values.
+    Fixed(usize, usize),
+    /// Pops as many values as the operand at this index plus a fixed number,
+    /// then pushes one.
+    PopsOperand(usize, usize),
+}
 
-    StackLoadLocalVar,
-    StackLoadLocalVarArr,
-    StackLoadLocalVarStr,
-    DropLocalArr,
-    DropLocalStr,
-    FunctionCall,
+#[derive(Debug)]
+pub struct OpInfo {
+    pub opcode: OpCode,
+    pub mnemonic: &'static str,
+    /// Width in bytes of each operand following the opcode.
+    pub operand_widths: &'static [usize],
+    pub stack_effect: StackEffect,
+}
 
-    ConstructArray,
+/// Decoded operands of an instruction. No opcode takes more than two.
+pub type Operands = [usize; 2];
 
-    ConcatArr,
-    ConcatStr,
+impl OpInfo {
+    pub fn operands_size(&self) -> usize {
+        self.operand_widths.iter().sum()
+    }
+    /// Reads the little endian operands from the bytes following the opcode.
+    #[inline]
+    pub fn decode_operands(&self, bytes: &[u8]) -> Operands {
+        let mut operands = [0; 2];
+        let mut start = 0;
+        for (operand, width) in operands.iter_mut().zip(self.operand_widths) {
+            for (shift, byte) in bytes[start..start + width].iter().enumerate() {
+                *operand |= (*byte as usize) << (8 * shift);
+            }
+            start += width;
+        }
+        operands
+    }
+}
 
-    LenArr,
-    LenStr,
+macro_rules! opcodes {
+    ($($name:ident [$($width:expr),*] $effect:expr;)*) => {
+        #[repr(u8)]
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        pub enum OpCode {
+            $($name,)*
+        }
 
-    Index,
+        /// Every opcode in discriminant order. This is the single source of
+        /// truth for encoding, decoding and disassembling instructions.
+        pub const OPCODES: &[OpInfo] = &[
+            $(OpInfo {
+                opcode: OpCode::$name,
+                mnemonic: stringify!($name),
+                operand_widths: &[$($width),*],
+                stack_effect: $effect,
+            },)*
+        ];
+    };
+}
 
-    And,
-    Or,
+use StackEffect::{Fixed, PopsOperand};
 
-    Mod,
+opcodes! {
+    Return [] Fixed(1, 0);
+    Constant [1] Fixed(0, 1);
+
+    AddI [] Fixed(2, 1);
+    SubtractI [] Fixed(2, 1);
+    MultiplyI [] Fixed(2, 1);
+    DivideI [] Fixed(2, 1);
+
+    AddF [] Fixed(2, 1);
+    SubtractF [] Fixed(2, 1);
+    MultiplyF [] Fixed(2, 1);
+    DivideF [] Fixed(2, 1);
+
+    True [] Fixed(0, 1);
+    False [] Fixed(0, 1);
+
+    EqualI [] Fixed(2, 1);
+    EqualF [] Fixed(2, 1);
+    EqualS [] Fixed(2, 1);
+    EqualB [] Fixed(2, 1);
+
+    GreaterThanI [] Fixed(2, 1);
+    LessThanI [] Fixed(2, 1);
+    GreaterThanOrEqualI [] Fixed(2, 1);
+    LessThanOrEqualI [] Fixed(2, 1);
+
+    GreaterThanF [] Fixed(2, 1);
+    LessThanF [] Fixed(2, 1);
+    GreaterThanOrEqualF [] Fixed(2, 1);
+    LessThanOrEqualF [] Fixed(2, 1);
+
+    Advance [1] Fixed(0, 0);
+    AdvanceIfFalse [1] Fixed(1, 0);
+
+    Not [] Fixed(1, 1);
+
+    StackLoadLocalVar [1] Fixed(0, 1);
+    FunctionCall [1, 1] PopsOperand(1, 0);
+
+    ConstructArray [1] PopsOperand(0, 0);
+
+    ConcatArr [] Fixed(2, 1);
+    ConcatStr [] Fixed(2, 1);
+
+    LenArr [] Fixed(1, 1);
+    LenStr [] Fixed(1, 1);
+
+    Index [] Fixed(2, 1);
+
+    And [] Fixed(2, 1);
+    Or [] Fixed(2, 1);
+
+    Mod [] Fixed(2, 1);
+
+    // Variants with four byte operands, emitted when an operand does not fit
+    // in one byte.
+    ConstantLong [4] Fixed(0, 1);
+    AdvanceLong [4] Fixed(0, 0);
+    AdvanceIfFalseLong [4] Fixed(1, 0);
+    StackLoadLocalVarLong [4] Fixed(0, 1);
+    FunctionCallLong [4, 4] PopsOperand(1, 0);
+    ConstructArrayLong [4] PopsOperand(0, 0);
+
+    // Move the top of the stack into a new local slot, and remove the last
+    // local slot once its binding goes out of scope.
+    BindLocal [] Fixed(1, 0);
+    PopLocal [] Fixed(0, 0);
+
+    // Push a function as a value, and call the function value below the
+    // arguments on the stack.
+    PushFunction [1] Fixed(0, 1);
+    PushFunctionLong [4] Fixed(0, 1);
+    CallIndirect [1] PopsOperand(0, 1);
+    CallIndirectLong [4] PopsOperand(0, 1);
+
+    // Build a closure from a function and the captured values on top of the
+    // stack.
+    MakeClosure [1, 1] PopsOperand(1, 0);
+    MakeClosureLong [4, 4] PopsOperand(1, 0);
+
+    // Calls that replace the current frame instead of returning to it. The
+    // pushed value stands for the callee's result.
+    TailCall [1, 1] PopsOperand(1, 0);
+    TailCallLong [4, 4] PopsOperand(1, 0);
+    TailCallIndirect [1] PopsOperand(0, 1);
+    TailCallIndirectLong [4] PopsOperand(0, 1);
+
+    // Call a function the host implements, which has no chunk or frame.
+    NativeCall [1, 1] PopsOperand(1, 0);
+    NativeCallLong [4, 4] PopsOperand(1, 0);
+
+    // Convert between int and float. Floats are truncated towards zero.
+    ToFloat [] Fixed(1, 1);
+    ToInt [] Fixed(1, 1);
+    // Format an int, float or bool as a string.
+    ToString [] Fixed(1, 1);
+
+    // Array functions. Range builds the ints from the first operand up to
+    // the second, and Slice takes the items of an array between two indices.
+    Range [] Fixed(2, 1);
+    Slice [] Fixed(3, 1);
+    Reverse [] Fixed(1, 1);
+    SortI [] Fixed(1, 1);
+    SortF [] Fixed(1, 1);
+    SortS [] Fixed(1, 1);
+    Contains [] Fixed(2, 1);
+    IndexOf [] Fixed(2, 1);
+
+    // Console I/O. Printing leaves the printed value on the stack, and reads
+    // push a one item array, which is empty at the end of the input.
+    Print [] Fixed(1, 1);
+    Println [] Fixed(1, 1);
+    ReadLine [] Fixed(0, 1);
+    ReadInt [] Fixed(0, 1);
+
+    // Drop the value of an expression evaluated only for its effects.
+    Pop [] Fixed(1, 0);
+
+    NullCode [] Fixed(0, 0);
+}
 
-    NullCode,
+impl OpCode {
+    #[inline]
+    pub fn info(self) -> &'static OpInfo {
+        &OPCODES[self as usize]
+    }
 }
+
 impl From<u8> for OpCode {
+    #[inline]
     fn from(value: u8) -> Self {
-        match value {
-            0 => OpCode::Return,
-            1 => OpCode::Constant,
-
-            2 => OpCode::AddI,
-            3 => OpCode::SubtractI,
-            4 => OpCode::MultiplyI,
-            5 => OpCode::DivideI,
-
-            6 => OpCode::AddF,
-            7 => OpCode::SubtractF,
-            8 => OpCode::MultiplyF,
-            9 => OpCode::DivideF,
-
-            10 => OpCode::True,
-            11 => OpCode::False,
-
-            12 => OpCode::EqualI,
-            13 => OpCode::EqualF,
-            14 => OpCode::EqualS,
-            15 => OpCode::EqualB,
-
-            16 => OpCode::GreaterThanI,
-            17 => OpCode::LessThanI,
-            18 => OpCode::GreaterThanOrEqualI,
-            19 => OpCode::LessThanOrEqualI,
-
-            20 => OpCode::GreaterThanF,
-            21 => OpCode::LessThanF,
-            22 => OpCode::GreaterThanOrEqualF,
-            23 => OpCode::LessThanOrEqualF,
-
-            24 => OpCode::Advance,
-            25 => OpCode::AdvanceIfFalse,
-
-            26 => OpCode::Not,
-
-            27 => OpCode::StackLoadLocalVar,
-            28 => OpCode::FunctionCall,
-
-            29 => OpCode::ConstructArray,
-
-            30 => OpCode::ConcatArr,
-            31 => OpCode::ConcatStr,
-
-            32 => OpCode::LenArr,
-            33 => OpCode::LenStr,
+        OPCODES
+            .get(value as usize)
+            .map_or(OpCode::NullCode, |info| info.opcode)
+    }
+}
 
-            34 => OpCode::Index,
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-            35 => OpCode::And,
-            36 => OpCode::Or,
+    #[test]
+    fn opcode_table_matches_discriminants() {
+        for (i, info) in OPCODES.iter().enumerate() {
+            assert_eq!(info.opcode as usize, i, "{} is out of order", info.mnemonic);
+            assert_eq!(format!("{:?}", info.opcode), info.mnemonic);
+        }
+    }
 
-            37 => OpCode::Mod,
+    #[test]
+    fn operands_fit_decoded_form() {
+        for info in OPCODES {
+            assert!(info.operand_widths.len() <= 2, "{}", info.mnemonic);
+            assert!(
+                info.operand_widths.iter().all(|w| *w <= 4),
+                "{}",
+                info.mnemonic
+            );
+        }
+        let info = OpCode::FunctionCallLong.info();
+        assert_eq!(
+            info.decode_operands(&[0x34, 0x12, 0, 0, 3, 0, 0, 0]),
+            [0x1234, 3]
+        );
+    }
 
-            _ => OpCode::NullCode,
+    #[test]
+    fn every_byte_round_trips() {
+        for byte in 0..=u8::MAX {
+            let opcode = OpCode::from(byte);
+            if (byte as usize) < OPCODES.len() {
+                assert_eq!(opcode as u8, byte);
+                assert_eq!(opcode.info().opcode, opcode);
+            } else {
+                assert_eq!(opcode, OpCode::NullCode);
+            }
         }
     }
 }
@@ -4,33 +4,141 @@ use crate::parser::*;
 use crate::tokenizer::PreToken;
 use crate::tokenizer::*;
 use crate::value::*;
+use std::error::Error;
+use std::fmt;
 
+#[derive(Debug, Clone)]
+pub struct CompileError {
+    pub span: Option<Span>,
+    pub message: String,
+}
+
+impl CompileError {
+    pub fn new(message: String, span: Option<Span>) -> CompileError {
+        CompileError { span, message }
+    }
+}
+
+impl From<String> for CompileError {
+    fn from(message: String) -> Self {
+        CompileError::new(message, None)
+    }
+}
+
+impl From<&str> for CompileError {
+    fn from(message: &str) -> Self {
+        CompileError::new(message.to_string(), None)
+    }
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.span {
+            Some(span) => {
+                writeln!(f, "Error at {}:\n\t{}", span, self.message)?;
+                span.fmt_source(f)
+            }
+            None => write!(f, "Error:\n\t{}", self.message),
+        }
+    }
+}
+impl Error for CompileError {}
+
+#[inline]
+pub fn get_next(token_stream: &[Token]) -> Option<TokenKind> {
+    token_stream.last().map(|t| t.kind.clone())
+}
 #[inline]
-pub fn get_next(token_stream: &[Token]) -> Option<Token> {
-    token_stream.last().cloned()
+pub fn get_sec_next(token_stream: &[Token]) -> Option<TokenKind> {
+    token_stream
+        .len()
+        .checked_sub(2)
+        .and_then(|i| token_stream.get(i))
+        .map(|t| t.kind.clone())
 }
 #[inline]
-pub fn get_sec_next(token_stream: &[Token]) -> Option<Token> {
-    token_stream.get(token_stream.len() - 2).cloned()
+pub fn get_span(token_stream: &[Token]) -> Option<Span> {
+    token_stream.last().map(|t| t.span.clone())
+}
+
+/// Picks `short` if every operand fits in a byte and `long` otherwise, failing
+/// if an operand does not fit in the long form either.
+fn operand_width(
+    short: OpCode,
+    long: OpCode,
+    operands: &[usize],
+    what: &str,
+) -> Result<OpCode, CompileError> {
+    let fits = |opcode: OpCode| {
+        operands
+            .iter()
+            .zip(opcode.info().operand_widths)
+            .all(|(operand, width)| (*operand as u64) < 1 << (8 * width))
+    };
+    if fits(short) {
+        Ok(short)
+    } else if fits(long) {
+        Ok(long)
+    } else {
+        Err(format!("{what} is too large to encode").into())
+    }
+}
+
+fn emit(
+    chunk: &mut Chunk,
+    short: OpCode,
+    long: OpCode,
+    operands: &[usize],
+    what: &str,
+) -> Result<(), CompileError> {
+    let opcode = operand_width(short, long, operands, what)?;
+    chunk.add_instruction(opcode, operands);
+    Ok(())
+}
+
+/// Pushes a copy of the local in `slot`.
+fn load_local(chunk: &mut Chunk, slot: usize) -> Result<(), CompileError> {
+    emit(
+        chunk,
+        OpCode::StackLoadLocalVar,
+        OpCode::StackLoadLocalVarLong,
+        &[slot],
+        "Local slot",
+    )
 }
 
 fn consume_function_args(
     token_stream: &mut Vec<Token>,
     local_variables: &mut Vec<(String, Type)>,
-) -> Result<(), String> {
-    while get_next(token_stream) != Some(Token::Lang(PreToken::KW(Keyword::Kerchow))) {
+) -> Result<(), CompileError> {
+    while get_next(token_stream) != Some(TokenKind::Lang(PreToken::KW(Keyword::Kerchow)))
+        && get_next(token_stream) != Some(TokenKind::Lang(PreToken::KW(Keyword::Bar)))
+    {
         let name = match get_next(token_stream) {
-            Some(Token::Symb(n)) => n.name(),
-            _ => return Err("Expected argument name".to_string()),
+            Some(TokenKind::Symb(n)) => n.name(),
+            _ => {
+                return Err(CompileError::new(
+                    "Expected argument name".to_string(),
+                    get_span(token_stream),
+                ));
+            }
         };
         token_stream.pop();
-        if get_next(token_stream) != Some(Token::Lang(PreToken::DEL(Delimeter::Colon))) {
-            return Err("Expected colon".to_string());
+        if get_next(token_stream) != Some(TokenKind::Lang(PreToken::DEL(Delimeter::Colon))) {
+            return Err(CompileError::new(
+                "Expected colon".to_string(),
+                get_span(token_stream),
+            ));
         }
         token_stream.pop();
         let t = match get_next(token_stream) {
-            Some(Token::Lang(PreToken::TYPE(t))) => t,
-            _ => return Err("Expected type".to_string()),
+            Some(TokenKind::Lang(PreToken::TYPE(t))) => t,
+            _ => {
+                return Err(CompileError::new(
+                    "Expected type".to_string(),
+                    get_span(token_stream),
+                ));
+            }
         };
         token_stream.pop();
         local_variables.push((name, t));
@@ -38,587 +146,1707 @@ fn consume_function_args(
     Ok(())
 }
 
+/// How deeply expressions may nest, so a crafted source cannot exhaust the
+/// stack while it compiles.
+const MAX_NESTING: usize = 200;
+
+/// Where an expression sits: how many expressions enclose it, and whether
+/// the function returns its value.
+#[derive(Clone, Copy)]
+struct Nesting {
+    depth: usize,
+    tail: bool,
+}
+
 fn consume_eval(
     chunk: &mut Chunk,
     token_stream: &mut Vec<Token>,
     local_variables: &Vec<(String, Type)>,
-    function_signatures: &Vec<(String, Vec<Type>, Type)>,
-    constants: &mut Vec<Value>,
-) -> Result<Type, String> {
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    depth: usize,
+) -> Result<Type, CompileError> {
+    consume_eval_at(
+        chunk,
+        token_stream,
+        local_variables,
+        function_signatures,
+        constants,
+        lambdas,
+        Nesting { depth, tail: false },
+    )
+}
+
+/// Compiles an expression whose value the function returns. Calls in tail
+/// position reuse the current frame instead of pushing a new one, so they
+/// never come back here.
+fn consume_tail(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    depth: usize,
+) -> Result<Type, CompileError> {
+    consume_eval_at(
+        chunk,
+        token_stream,
+        local_variables,
+        function_signatures,
+        constants,
+        lambdas,
+        Nesting { depth, tail: true },
+    )
+}
+
+/// Compiles one expression. Errors raised while compiling it that do not
+/// carry a location of their own, and the instructions emitted for it that
+/// nested expressions did not claim, point at the expression's first token.
+fn consume_eval_at(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    at: Nesting,
+) -> Result<Type, CompileError> {
+    let span = get_span(token_stream);
+    let at = Nesting {
+        depth: at.depth + 1,
+        ..at
+    };
+    if at.depth > MAX_NESTING {
+        return Err(CompileError::new(
+            "Expression nested too deeply".to_string(),
+            span,
+        ));
+    }
+    let start = chunk.get_length();
+    let t = consume_expression(
+        chunk,
+        token_stream,
+        local_variables,
+        function_signatures,
+        constants,
+        lambdas,
+        at,
+    )
+    .map_err(|mut e| {
+        if e.span.is_none() {
+            e.span = span.clone();
+        }
+        e
+    })?;
+    chunk.mark_positions(start, &span);
+    Ok(t)
+}
+
+/// Passes a bare name of the `expected` function type as a value instead of
+/// calling it. Returns whether the next token was such a name.
+fn consume_function_value(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &[(String, Type)],
+    function_signatures: &[FunctionSignature],
+    expected: &Type,
+) -> Result<bool, CompileError> {
+    let Type::Function(_, _) = expected else {
+        return Ok(false);
+    };
+    let Some(TokenKind::Symb(s)) = get_next(token_stream) else {
+        return Ok(false);
+    };
+    let span = get_span(token_stream);
+    let start = chunk.get_length();
+    let local = local_variables
+        .iter()
+        .rposition(|(name, _)| *name == s.name());
+    let function = function_signatures.iter().rposition(|f| f.name == s.name());
+    match (local, function) {
+        (Some(i), _) if local_variables[i].1 == *expected => {
+            load_local(chunk, i)?;
+        }
+        (None, Some(i))
+            if Type::Function(
+                function_signatures[i].params.clone(),
+                Box::new(function_signatures[i].ret.clone()),
+            ) == *expected =>
+        {
+            emit(
+                chunk,
+                OpCode::PushFunction,
+                OpCode::PushFunctionLong,
+                &[i],
+                "Function index",
+            )?;
+        }
+        _ => return Ok(false),
+    }
+    token_stream.pop();
+    chunk.mark_positions(start, &span);
+    Ok(true)
+}
+
+/// Compiles a lambda `params => body` into a function of its own, which is
+/// added to `lambdas`, and builds a closure over it. The closure captures
+/// the enclosing locals the body uses, copying them into the lambda's first
+/// parameter slots.
+fn consume_lambda(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &[(String, Type)],
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    depth: usize,
+) -> Result<Type, CompileError> {
+    let span = get_span(token_stream);
+    let mut params: Vec<(String, Type)> = Vec::new();
+    consume_function_args(token_stream, &mut params)?;
+    if get_next(token_stream) != Some(TokenKind::Lang(PreToken::KW(Keyword::Kerchow))) {
+        return Err(CompileError::new(
+            "Expected kerchow".to_string(),
+            get_span(token_stream),
+        ));
+    }
+    token_stream.pop();
+
+    // every enclosing local is in scope while the body compiles, and the
+    // ones it loads become the captures once it is done
+    let mut lambda_variables = local_variables.to_vec();
+    lambda_variables.extend(params.iter().cloned());
+    let index = function_signatures.len();
+    let slot = lambdas.len();
+    // the parameters and return type are filled in once the body is compiled
+    function_signatures.push(FunctionSignature::new(
+        match &span {
+            Some(span) => format!("<lambda at {span}>"),
+            None => "<lambda>".to_string(),
+        },
+        Vec::new(),
+        Type::AnyType,
+    ));
+    lambdas.push(Chunk::new(Vec::new()));
+
+    let mut body = Chunk::new(Vec::new());
+    let ret = consume_tail(
+        &mut body,
+        token_stream,
+        &lambda_variables,
+        function_signatures,
+        constants,
+        lambdas,
+        depth,
+    )?;
+    body.add_opcode(OpCode::Return);
+    body.mark_positions(0, &span);
+
+    let mut loaded = vec![false; local_variables.len()];
+    body.map_local_slots(|slot| {
+        if let Some(used) = loaded.get_mut(slot) {
+            *used = true;
+        }
+        slot
+    });
+    let captures: Vec<usize> = (0..local_variables.len()).filter(|i| loaded[*i]).collect();
+    // captures come first, then the parameters and the body's own locals;
+    // slots only get smaller, so every load keeps its width
+    body.map_local_slots(|slot| match captures.binary_search(&slot) {
+        Ok(i) => i,
+        Err(_) => slot - local_variables.len() + captures.len(),
+    });
+    function_signatures[index].params = captures
+        .iter()
+        .map(|i| local_variables[*i].1.clone())
+        .chain(params.iter().map(|(_, t)| t.clone()))
+        .collect();
+    function_signatures[index].ret = ret.clone();
+    lambdas[slot] = body;
+
+    for i in &captures {
+        load_local(chunk, *i)?;
+    }
+    emit(
+        chunk,
+        OpCode::MakeClosure,
+        OpCode::MakeClosureLong,
+        &[index, captures.len()],
+        "Function index",
+    )?;
+    Ok(Type::Function(
+        params.into_iter().map(|(_, t)| t).collect(),
+        Box::new(ret),
+    ))
+}
+
+fn consume_expression(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    at: Nesting,
+) -> Result<Type, CompileError> {
     match get_next(token_stream) {
-        Some(Token::Lit(l)) => {
-            chunk.add_opcode(OpCode::Constant);
-            chunk.add_byte(constants.len() as u8);
-            constants.push(val_from_literal(l.clone()));
+        Some(TokenKind::Lit(l)) => {
+            emit(
+                chunk,
+                OpCode::Constant,
+                OpCode::ConstantLong,
+                &[constants.len()],
+                "Constant index",
+            )?;
+            constants.push(l.clone());
 
             token_stream.pop();
             Ok(l.get_type())
         }
-        Some(Token::Lang(PreToken::DEL(Delimeter::LBracket))) => {
+        Some(TokenKind::Lang(PreToken::DEL(Delimeter::LBracket))) => consume_array_literal(
+            chunk,
+            token_stream,
+            local_variables,
+            function_signatures,
+            constants,
+            lambdas,
+            at.depth,
+        ),
+        Some(TokenKind::Lang(PreToken::KW(Keyword::Kick))) => {
+            // kick first then: runs first for its effects, then evaluates to then
             token_stream.pop();
-            let mut t: Option<Type> = None;
-            let mut num: u8 = 0;
-            while get_next(token_stream) != Some(Token::Lang(PreToken::DEL(Delimeter::RBracket))) {
-                let in_type = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if t.is_none() {
-                    t = Some(in_type);
-                } else if t.clone().unwrap() != in_type {
-                    return Err(format!(
-                        "aaa Type mismatch, expected {:?}, got {:?}",
-                        t.clone().unwrap(),
-                        in_type
-                    ));
+            consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                at.depth,
+            )?;
+            chunk.add_opcode(OpCode::Pop);
+            consume_eval_at(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                at,
+            )
+        }
+        Some(TokenKind::Lang(PreToken::KW(Keyword::Punch))) => consume_punch(
+            chunk,
+            token_stream,
+            local_variables,
+            function_signatures,
+            constants,
+            lambdas,
+            at,
+        ),
+        Some(TokenKind::Symb(_))
+            if get_sec_next(token_stream)
+                == Some(TokenKind::Lang(PreToken::DEL(Delimeter::Colon))) =>
+        {
+            consume_lambda(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                at.depth,
+            )
+        }
+        Some(TokenKind::Lang(PreToken::KW(Keyword::Kerchow))) => consume_lambda(
+            chunk,
+            token_stream,
+            local_variables,
+            function_signatures,
+            constants,
+            lambdas,
+            at.depth,
+        ),
+        Some(TokenKind::Symb(_)) => consume_symbol(
+            chunk,
+            token_stream,
+            local_variables,
+            function_signatures,
+            constants,
+            lambdas,
+            at,
+        ),
+        Some(TokenKind::Lang(PreToken::OP(op))) => {
+            // every family of operators compiles in a function of its own,
+            // so each level of nesting only takes the stack its family needs
+            let consume = match op {
+                Operator::Cond => {
+                    return consume_cond(
+                        chunk,
+                        token_stream,
+                        local_variables,
+                        function_signatures,
+                        constants,
+                        lambdas,
+                        at,
+                    );
                 }
-                num += 1;
+                Operator::Or | Operator::And | Operator::Not => consume_logic,
+                Operator::Gt
+                | Operator::Lt
+                | Operator::Geq
+                | Operator::Leq
+                | Operator::Eq
+                | Operator::Neq => consume_comparison,
+                Operator::Plus
+                | Operator::Minus
+                | Operator::Mult
+                | Operator::Div
+                | Operator::Mod => consume_arithmetic,
+                Operator::Concat
+                | Operator::Index
+                | Operator::Length
+                | Operator::Range
+                | Operator::Slice
+                | Operator::Reverse
+                | Operator::Sort
+                | Operator::Contains
+                | Operator::IndexOf => consume_array_operator,
+                Operator::ToFloat | Operator::ToInt | Operator::ToString => consume_conversion,
+                Operator::Print | Operator::Println | Operator::ReadLine | Operator::ReadInt => {
+                    consume_console
+                }
+            };
+            consume(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                at.depth,
+            )
+        }
+        _ => Err("Expected expression".into()),
+    }
+}
+
+/// Compiles an array literal, whose `[` is next.
+fn consume_array_literal(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    depth: usize,
+) -> Result<Type, CompileError> {
+    token_stream.pop();
+    let mut t: Option<Type> = None;
+    let mut num: usize = 0;
+    while get_next(token_stream) != Some(TokenKind::Lang(PreToken::DEL(Delimeter::RBracket))) {
+        let in_type = consume_eval(
+            chunk,
+            token_stream,
+            local_variables,
+            function_signatures,
+            constants,
+            lambdas,
+            depth,
+        )?;
+        if t.is_none() {
+            t = Some(in_type);
+        } else if t.clone().unwrap() != in_type {
+            return Err(format!(
+                "aaa Type mismatch, expected {:?}, got {:?}",
+                t.clone().unwrap(),
+                in_type
+            )
+            .into());
+        }
+        num += 1;
+    }
+    token_stream.pop();
+    if t.is_none() {
+        t = Some(Type::Array(Box::new(Type::AnyType)));
+    } else {
+        t = Some(Type::Array(Box::new(t.unwrap())));
+    }
+    emit(
+        chunk,
+        OpCode::ConstructArray,
+        OpCode::ConstructArrayLong,
+        &[num],
+        "Array literal",
+    )?;
+    Ok(t.unwrap())
+}
+
+/// Compiles `punch name := value body`, whose `punch` is next, binding a
+/// local that only the body can see.
+fn consume_punch(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    at: Nesting,
+) -> Result<Type, CompileError> {
+    token_stream.pop();
+    let name = match get_next(token_stream) {
+        Some(TokenKind::Symb(n)) => n.name(),
+        _ => {
+            return Err(CompileError::new(
+                "Expected binding name".to_string(),
+                get_span(token_stream),
+            ));
+        }
+    };
+    token_stream.pop();
+    if get_next(token_stream) != Some(TokenKind::Lang(PreToken::KW(Keyword::Define))) {
+        return Err(CompileError::new(
+            "Expected define".to_string(),
+            get_span(token_stream),
+        ));
+    }
+    token_stream.pop();
+    let value_type = consume_eval(
+        chunk,
+        token_stream,
+        local_variables,
+        function_signatures,
+        constants,
+        lambdas,
+        at.depth,
+    )?;
+    chunk.add_opcode(OpCode::BindLocal);
+
+    // the binding is only visible in the body, where it shadows any
+    // outer local of the same name
+    let mut body_variables = local_variables.clone();
+    body_variables.push((name, value_type));
+    let t = consume_eval_at(
+        chunk,
+        token_stream,
+        &body_variables,
+        function_signatures,
+        constants,
+        lambdas,
+        at,
+    )?;
+    chunk.add_opcode(OpCode::PopLocal);
+    Ok(t)
+}
+
+/// Compiles a use of the local or function named next, calling it if it is
+/// a function, or else the builtin operator of that name.
+fn consume_symbol(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    at: Nesting,
+) -> Result<Type, CompileError> {
+    let Some(TokenKind::Symb(s)) = get_next(token_stream) else {
+        return Err("Expected a name".into());
+    };
+    for (i, item) in local_variables.iter().enumerate().rev() {
+        if item.0 == s.name() {
+            let t = local_variables[i].1.clone();
+            load_local(chunk, i)?;
+            if let Type::Function(params, ret) = t {
+                // calling a function value: load it, then its arguments
+                token_stream.pop();
+                for param in &params {
+                    // a bare name of the parameter's function type is passed as a value
+                    if consume_function_value(
+                        chunk,
+                        token_stream,
+                        local_variables,
+                        function_signatures,
+                        param,
+                    )? {
+                        continue;
+                    }
+                    let arg = consume_eval(
+                        chunk,
+                        token_stream,
+                        local_variables,
+                        function_signatures,
+                        constants,
+                        lambdas,
+                        at.depth,
+                    )?;
+                    if arg != *param {
+                        return Err(format!("Type mismatch, expected {param}, got {arg}").into());
+                    }
+                }
+                if at.tail {
+                    emit(
+                        chunk,
+                        OpCode::TailCallIndirect,
+                        OpCode::TailCallIndirectLong,
+                        &[params.len()],
+                        "Argument count",
+                    )?;
+                } else {
+                    emit(
+                        chunk,
+                        OpCode::CallIndirect,
+                        OpCode::CallIndirectLong,
+                        &[params.len()],
+                        "Argument count",
+                    )?;
+                }
+                return Ok(*ret);
+            }
+            token_stream.pop();
+            return Ok(t);
+        }
+    }
+    if let Some(i) = function_signatures.iter().rposition(|f| f.name == s.name()) {
+        token_stream.pop();
+        let params = function_signatures[i].params.clone();
+        for param in &params {
+            if consume_function_value(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                param,
+            )? {
+                continue;
+            }
+            let t = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                at.depth,
+            )?;
+            if t != *param {
+                return Err(format!("Type mismatch, expected {param}, got {t}").into());
+            }
+        }
+        if function_signatures[i].native {
+            emit(
+                chunk,
+                OpCode::NativeCall,
+                OpCode::NativeCallLong,
+                &[i, params.len()],
+                "Function index",
+            )?;
+        } else if at.tail {
+            emit(
+                chunk,
+                OpCode::TailCall,
+                OpCode::TailCallLong,
+                &[i, params.len()],
+                "Function index",
+            )?;
+        } else {
+            emit(
+                chunk,
+                OpCode::FunctionCall,
+                OpCode::FunctionCallLong,
+                &[i, params.len()],
+                "Function index",
+            )?;
+        }
+        return Ok(function_signatures[i].ret.clone());
+    }
+    if let Some(op) = builtin(&s.name()) {
+        // nothing shadows the builtin, so compile it as an operator
+        token_stream.last_mut().unwrap().kind = TokenKind::Lang(PreToken::OP(op));
+        return consume_expression(
+            chunk,
+            token_stream,
+            local_variables,
+            function_signatures,
+            constants,
+            lambdas,
+            at,
+        );
+    }
+    Err(format!("Unknown symbol {s}").into())
+}
+
+/// Compiles `cond`, whose token is next. Either branch may be in tail
+/// position.
+fn consume_cond(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    at: Nesting,
+) -> Result<Type, CompileError> {
+    token_stream.pop();
+    let cond_type = consume_eval(
+        chunk,
+        token_stream,
+        local_variables,
+        function_signatures,
+        constants,
+        lambdas,
+        at.depth,
+    )?;
+    if cond_type != Type::Bool {
+        return Err("Type mismatch".into());
+    }
+    let mut chunk1 = Chunk::new(Vec::new());
+    let type1 = consume_eval_at(
+        &mut chunk1,
+        token_stream,
+        local_variables,
+        function_signatures,
+        constants,
+        lambdas,
+        at,
+    )?;
+    let eval1len = chunk1.get_length();
+    let mut chunk2 = Chunk::new(Vec::new());
+    let type2 = consume_eval_at(
+        &mut chunk2,
+        token_stream,
+        local_variables,
+        function_signatures,
+        constants,
+        lambdas,
+        at,
+    )?;
+    let eval2len = chunk2.get_length();
+    if type1 != type2 {
+        return Err("Type mismatch".into());
+    }
+    // the false branch jump skips the true branch and its jump to the end
+    let advance = operand_width(OpCode::Advance, OpCode::AdvanceLong, &[eval2len], "Jump")?;
+    let skip = eval1len + 1 + advance.info().operands_size();
+    emit(
+        chunk,
+        OpCode::AdvanceIfFalse,
+        OpCode::AdvanceIfFalseLong,
+        &[skip],
+        "Jump",
+    )?;
+    chunk.add_chunk(&mut chunk1);
+    drop(chunk1);
+    chunk.add_instruction(advance, &[eval2len]);
+    chunk.add_chunk(&mut chunk2);
+    drop(chunk2);
+    Ok(type1)
+}
+
+/// Compiles `op`, one of the boolean operators, whose token is next.
+fn consume_logic(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    depth: usize,
+) -> Result<Type, CompileError> {
+    let Some(TokenKind::Lang(PreToken::OP(op))) = get_next(token_stream) else {
+        return Err("Expected an operator".into());
+    };
+    match op {
+        Operator::Or => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != Type::Bool {
+                return Err(format!("Type mismatch, expected bool, got {type1}",).into());
+            }
+            if type2 != Type::Bool {
+                return Err(format!("Type mismatch, expected bool, got {type2}",).into());
             }
-            chunk.add_opcode(OpCode::ConstructArray);
-            chunk.add_byte(num);
+            chunk.add_opcode(OpCode::Or);
+            Ok(Type::Bool)
+        }
+        Operator::And => {
             token_stream.pop();
-            if t.is_none() {
-                t = Some(Type::Array(Box::new(Type::AnyType)));
-                chunk.add_byte(0);
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != Type::Bool {
+                return Err(format!("Type mismatch, expected bool, got {type1}",).into());
+            }
+            if type2 != Type::Bool {
+                return Err(format!("Type mismatch, expected bool, got {type2}",).into());
+            }
+            chunk.add_opcode(OpCode::And);
+            Ok(Type::Bool)
+        }
+        Operator::Not => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != Type::Bool {
+                return Err(format!("Type mismatch, expected bool, got {type1}",).into());
+            }
+            chunk.add_opcode(OpCode::Not);
+            Ok(Type::Bool)
+        }
+        _ => unreachable!("{op:?} is compiled elsewhere"),
+    }
+}
+
+/// Compiles `op`, one of the comparison operators, whose token is next.
+fn consume_comparison(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    depth: usize,
+) -> Result<Type, CompileError> {
+    let Some(TokenKind::Lang(PreToken::OP(op))) = get_next(token_stream) else {
+        return Err("Expected an operator".into());
+    };
+    match op {
+        Operator::Gt => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != type2 {
+                return Err(format!("Type mismatch, expected {type1}, got {type2}",).into());
+            }
+            if type1 == Type::Int {
+                chunk.add_opcode(OpCode::GreaterThanI);
+            } else if type1 == Type::Float {
+                chunk.add_opcode(OpCode::GreaterThanF);
             } else {
-                t = Some(Type::Array(Box::new(t.unwrap())));
-                chunk.add_byte(t.clone().unwrap().array_depth());
-            }
-            Ok(t.unwrap())
-        }
-        Some(Token::Symb(s)) => {
-            for (i, item) in local_variables.iter().enumerate() {
-                if item.0 == s.name() {
-                    let t = local_variables[i].1.clone();
-                    match t {
-                        Type::String => {
-                            chunk.add_opcode(OpCode::StackLoadLocalVarStr);
-                            chunk.add_byte(i as u8);
-                        }
-                        Type::Array(_) => {
-                            chunk.add_opcode(OpCode::StackLoadLocalVarArr);
-                            chunk.add_byte(i as u8);
-                            chunk.add_byte(t.array_depth());
-                        }
-                        _ => {
-                            chunk.add_opcode(OpCode::StackLoadLocalVar);
-                            chunk.add_byte(i as u8);
-                        }
-                    }
-                    token_stream.pop();
-                    return Ok(t);
-                }
+                return Err(format!("Type mismatch, expected {type1}, got {type2}",).into());
             }
-            for (i, item) in function_signatures.iter().enumerate() {
-                if item.0 == s.name() {
-                    token_stream.pop();
-                    for j in 0..item.1.len() {
-                        let t = consume_eval(
-                            chunk,
-                            token_stream,
-                            local_variables,
-                            function_signatures,
-                            constants,
-                        )?;
-                        if t != function_signatures[i].1[j] {
-                            return Err(format!(
-                                "Type mismatch, expected {:?}, got {:?}",
-                                function_signatures[i].1[j], t
-                            ));
-                        }
-                    }
-                    chunk.add_opcode(OpCode::FunctionCall);
-                    chunk.add_byte(i as u8);
-                    chunk.add_byte(item.1.len() as u8);
-                    return Ok(function_signatures[i].2.clone());
-                }
+            Ok(Type::Bool)
+        }
+        Operator::Lt => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != type2 {
+                return Err("Type mismatch".into());
+            }
+            if type1 == Type::Int {
+                chunk.add_opcode(OpCode::LessThanI);
+            } else if type1 == Type::Float {
+                chunk.add_opcode(OpCode::LessThanF);
+            } else {
+                return Err("Type mismatch".into());
             }
-            Err("Unknown symbol".to_string())
+            Ok(Type::Bool)
         }
-        Some(Token::Lang(PreToken::OP(op))) => match op {
-            Operator::Or => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != Type::Bool {
-                    return Err(format!("Type mismatch, expected bool, got {type1}",));
-                }
-                if type2 != Type::Bool {
-                    return Err(format!("Type mismatch, expected bool, got {type2}",));
-                }
-                chunk.add_opcode(OpCode::Or);
-                Ok(Type::Bool)
+        Operator::Geq => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != type2 {
+                return Err("Type mismatch".into());
             }
-            Operator::And => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != Type::Bool {
-                    return Err(format!("Type mismatch, expected bool, got {type1}",));
-                }
-                if type2 != Type::Bool {
-                    return Err(format!("Type mismatch, expected bool, got {type2}",));
-                }
-                chunk.add_opcode(OpCode::And);
-                Ok(Type::Bool)
+            if type1 == Type::Int {
+                chunk.add_opcode(OpCode::GreaterThanOrEqualI);
+            } else if type1 == Type::Float {
+                chunk.add_opcode(OpCode::GreaterThanOrEqualF);
+            } else {
+                return Err("Type mismatch".into());
             }
-            Operator::Gt => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != type2 {
-                    return Err(format!("Type mismatch, expected {type1}, got {type2}",));
-                }
-                if type1 == Type::Int {
-                    chunk.add_opcode(OpCode::GreaterThanI);
-                } else if type1 == Type::Float {
-                    chunk.add_opcode(OpCode::GreaterThanF);
-                } else {
-                    return Err(format!("Type mismatch, expected {type1}, got {type2}",));
-                }
-                Ok(Type::Bool)
+            Ok(Type::Bool)
+        }
+        Operator::Leq => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != type2 {
+                return Err("Type mismatch".into());
             }
-            Operator::Lt => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != type2 {
-                    return Err("Type mismatch".to_string());
-                }
-                if type1 == Type::Int {
-                    chunk.add_opcode(OpCode::LessThanI);
-                } else if type1 == Type::Float {
-                    chunk.add_opcode(OpCode::LessThanF);
-                } else {
-                    return Err("Type mismatch".to_string());
-                }
-                Ok(Type::Bool)
+            if type1 == Type::Int {
+                chunk.add_opcode(OpCode::LessThanOrEqualI);
+            } else if type1 == Type::Float {
+                chunk.add_opcode(OpCode::LessThanOrEqualF);
+            } else {
+                return Err("Type mismatch".into());
             }
-            Operator::Geq => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != type2 {
-                    return Err("Type mismatch".to_string());
-                }
-                if type1 == Type::Int {
-                    chunk.add_opcode(OpCode::GreaterThanOrEqualI);
-                } else if type1 == Type::Float {
-                    chunk.add_opcode(OpCode::GreaterThanOrEqualF);
-                } else {
-                    return Err("Type mismatch".to_string());
-                }
-                Ok(Type::Bool)
+            Ok(Type::Bool)
+        }
+        Operator::Eq => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != type2 {
+                return Err(format!("Type mismatch, got {type1} and {type2}",).into());
             }
-            Operator::Leq => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != type2 {
-                    return Err("Type mismatch".to_string());
-                }
-                if type1 == Type::Int {
-                    chunk.add_opcode(OpCode::LessThanOrEqualI);
-                } else if type1 == Type::Float {
-                    chunk.add_opcode(OpCode::LessThanOrEqualF);
-                } else {
-                    return Err("Type mismatch".to_string());
-                }
-                Ok(Type::Bool)
+            match type1 {
+                Type::Int => chunk.add_opcode(OpCode::EqualI),
+                Type::Float => chunk.add_opcode(OpCode::EqualF),
+                Type::Bool => chunk.add_opcode(OpCode::EqualB),
+                Type::String => chunk.add_opcode(OpCode::EqualS),
+                _ => return Err("Type mismatch".into()),
             }
-            Operator::Plus => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != type2 {
-                    return Err("Type mismatch".to_string());
-                }
-                if type1 == Type::Int {
-                    chunk.add_opcode(OpCode::AddI);
-                } else if type1 == Type::Float {
-                    chunk.add_opcode(OpCode::AddF);
-                } else {
-                    return Err("Type mismatch".to_string());
-                }
-                Ok(type1)
+            Ok(Type::Bool)
+        }
+        Operator::Neq => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != type2 {
+                return Err(format!("Type mismatch, got {type1} and {type2}",).into());
             }
-            Operator::Minus => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != type2 {
-                    return Err("Type mismatch".to_string());
-                }
-                if type1 == Type::Int {
-                    chunk.add_opcode(OpCode::SubtractI);
-                } else if type1 == Type::Float {
-                    chunk.add_opcode(OpCode::SubtractF);
-                } else {
-                    return Err("Type mismatch".to_string());
-                }
+            match type1 {
+                Type::Int => chunk.add_opcode(OpCode::EqualI),
+                Type::Float => chunk.add_opcode(OpCode::EqualF),
+                Type::Bool => chunk.add_opcode(OpCode::EqualB),
+                Type::String => chunk.add_opcode(OpCode::EqualS),
+                _ => return Err("Type mismatch".into()),
+            }
+            chunk.add_opcode(OpCode::Not);
+            Ok(Type::Bool)
+        }
+        _ => unreachable!("{op:?} is compiled elsewhere"),
+    }
+}
+
+/// Compiles `op`, one of the arithmetic operators, whose token is next.
+fn consume_arithmetic(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    depth: usize,
+) -> Result<Type, CompileError> {
+    let Some(TokenKind::Lang(PreToken::OP(op))) = get_next(token_stream) else {
+        return Err("Expected an operator".into());
+    };
+    match op {
+        Operator::Plus => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != type2 {
+                return Err("Type mismatch".into());
+            }
+            if type1 == Type::Int {
+                chunk.add_opcode(OpCode::AddI);
+            } else if type1 == Type::Float {
+                chunk.add_opcode(OpCode::AddF);
+            } else {
+                return Err("Type mismatch".into());
+            }
+            Ok(type1)
+        }
+        Operator::Minus => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != type2 {
+                return Err("Type mismatch".into());
+            }
+            if type1 == Type::Int {
+                chunk.add_opcode(OpCode::SubtractI);
+            } else if type1 == Type::Float {
+                chunk.add_opcode(OpCode::SubtractF);
+            } else {
+                return Err("Type mismatch".into());
+            }
+            Ok(type1)
+        }
+        Operator::Mult => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != type2 {
+                return Err("Type mismatch".into());
+            }
+            if type1 == Type::Int {
+                chunk.add_opcode(OpCode::MultiplyI);
+            } else if type1 == Type::Float {
+                chunk.add_opcode(OpCode::MultiplyF);
+            } else {
+                return Err("Type mismatch".into());
+            }
+            Ok(type1)
+        }
+        Operator::Div => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != type2 {
+                return Err("Type mismatch".into());
+            }
+            if type1 == Type::Int {
+                chunk.add_opcode(OpCode::DivideI);
+            } else if type1 == Type::Float {
+                chunk.add_opcode(OpCode::DivideF);
+            } else {
+                return Err("Type mismatch".into());
+            }
+            Ok(type1)
+        }
+        Operator::Mod => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != Type::Int || type2 != Type::Int {
+                return Err(format!("Type mismatch, expected ints, got {type1}, {type2}",).into());
+            }
+            chunk.add_opcode(OpCode::Mod);
+            Ok(Type::Int)
+        }
+        _ => unreachable!("{op:?} is compiled elsewhere"),
+    }
+}
+
+/// Compiles `op`, one of the array and string operators, whose token is next.
+fn consume_array_operator(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    depth: usize,
+) -> Result<Type, CompileError> {
+    let Some(TokenKind::Lang(PreToken::OP(op))) = get_next(token_stream) else {
+        return Err("Expected an operator".into());
+    };
+    match op {
+        Operator::Concat => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 == Type::String && type2 == Type::String {
+                chunk.add_opcode(OpCode::ConcatStr);
+                Ok(Type::String)
+            } else if let Type::Array(_) = type1
+                && type1 == type2
+            {
+                chunk.add_opcode(OpCode::ConcatArr);
                 Ok(type1)
+            } else {
+                Err("Type mismatch".into())
+            }
+        }
+        Operator::Index => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if let Type::Array(t) = type1
+                && type2 == Type::Int
+            {
+                chunk.add_opcode(OpCode::Index);
+                Ok(*t)
+            } else {
+                Err("Type mismatch".into())
+            }
+        }
+        Operator::Length => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if let Type::Array(_) = type1 {
+                chunk.add_opcode(OpCode::LenArr);
+                Ok(Type::Int)
+            } else if let Type::String = type1 {
+                chunk.add_opcode(OpCode::LenStr);
+                Ok(Type::Int)
+            } else {
+                Err("Type mismatch".into())
             }
-            Operator::Mult => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != type2 {
-                    return Err("Type mismatch".to_string());
-                }
-                if type1 == Type::Int {
-                    chunk.add_opcode(OpCode::MultiplyI);
-                } else if type1 == Type::Float {
-                    chunk.add_opcode(OpCode::MultiplyF);
-                } else {
-                    return Err("Type mismatch".to_string());
-                }
+        }
+        Operator::Range => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != Type::Int || type2 != Type::Int {
+                return Err(format!(
+                    "Type mismatch, expected int and int, got {type1} and {type2}"
+                )
+                .into());
+            }
+            chunk.add_opcode(OpCode::Range);
+            Ok(Type::Array(Box::new(Type::Int)))
+        }
+        Operator::Slice => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type3 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if let Type::Array(_) = type1
+                && type2 == Type::Int
+                && type3 == Type::Int
+            {
+                chunk.add_opcode(OpCode::Slice);
                 Ok(type1)
+            } else {
+                Err("Type mismatch".into())
             }
-            Operator::Div => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != type2 {
-                    return Err("Type mismatch".to_string());
-                }
-                if type1 == Type::Int {
-                    chunk.add_opcode(OpCode::DivideI);
-                } else if type1 == Type::Float {
-                    chunk.add_opcode(OpCode::DivideF);
-                } else {
-                    return Err("Type mismatch".to_string());
-                }
+        }
+        Operator::Reverse => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if let Type::Array(_) = type1 {
+                chunk.add_opcode(OpCode::Reverse);
                 Ok(type1)
+            } else {
+                Err(format!("Type mismatch, expected an array, got {type1}").into())
             }
-            Operator::Mod => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != Type::Int || type2 != Type::Int {
+        }
+        Operator::Sort => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            match &type1 {
+                Type::Array(t) if **t == Type::Int => chunk.add_opcode(OpCode::SortI),
+                Type::Array(t) if **t == Type::Float => chunk.add_opcode(OpCode::SortF),
+                Type::Array(t) if **t == Type::String => chunk.add_opcode(OpCode::SortS),
+                _ => {
                     return Err(format!(
-                        "Type mismatch, expected ints, got {type1}, {type2}",
-                    ));
+                        "Type mismatch, expected [int], [float] or [string], got {type1}"
+                    )
+                    .into());
                 }
-                chunk.add_opcode(OpCode::Mod);
-                Ok(Type::Int)
             }
-            Operator::Cond => {
-                token_stream.pop();
-                let cond_type = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if cond_type != Type::Bool {
-                    return Err("Type mismatch".to_string());
-                }
-                let mut chunk1 = Chunk::new(Vec::new());
-                let type1 = consume_eval(
-                    &mut chunk1,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let eval1len = chunk1.get_length() as u8;
-                let mut chunk2 = Chunk::new(Vec::new());
-                let type2 = consume_eval(
-                    &mut chunk2,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let eval2len = chunk2.get_length() as u8;
-                if type1 != type2 {
-                    return Err("Type mismatch".to_string());
-                }
-                chunk.add_opcode(OpCode::AdvanceIfFalse);
-                chunk.add_byte(eval1len + 2);
-                chunk.add_chunk(&mut chunk1);
-                drop(chunk1);
-                chunk.add_opcode(OpCode::Advance);
-                chunk.add_byte(eval2len);
-                chunk.add_chunk(&mut chunk2);
-                drop(chunk2);
-                Ok(type1)
+            Ok(type1)
+        }
+        Operator::Contains | Operator::IndexOf => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            let type2 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            // items are compared like ==, which only takes scalars
+            let Type::Array(t) = &type1 else {
+                return Err(format!("Type mismatch, expected an array, got {type1}").into());
+            };
+            if type2 != **t || !matches!(type2, Type::Int | Type::Float | Type::Bool | Type::String)
+            {
+                return Err(format!("Type mismatch, cannot look for {type2} in {type1}").into());
             }
-            Operator::Eq => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 != type2 {
-                    return Err(format!("Type mismatch, got {type1} and {type2}",));
-                }
-                match type1 {
-                    Type::Int => chunk.add_opcode(OpCode::EqualI),
-                    Type::Float => chunk.add_opcode(OpCode::EqualF),
-                    Type::Bool => chunk.add_opcode(OpCode::EqualB),
-                    Type::String => chunk.add_opcode(OpCode::EqualS),
-                    _ => return Err("Type mismatch".to_string()),
-                }
+            if op == Operator::Contains {
+                chunk.add_opcode(OpCode::Contains);
                 Ok(Type::Bool)
+            } else {
+                chunk.add_opcode(OpCode::IndexOf);
+                Ok(Type::Int)
             }
-            Operator::Concat => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if type1 == Type::String && type2 == Type::String {
-                    chunk.add_opcode(OpCode::ConcatStr);
-                    Ok(Type::String)
-                } else if let Type::Array(_) = type1
-                    && type1 == type2
-                {
-                    chunk.add_opcode(OpCode::ConcatArr);
-                    Ok(type1)
-                } else {
-                    return Err("Type mismatch".to_string());
-                }
+        }
+        _ => unreachable!("{op:?} is compiled elsewhere"),
+    }
+}
+
+/// Compiles `op`, one of the conversions, whose token is next.
+fn consume_conversion(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    depth: usize,
+) -> Result<Type, CompileError> {
+    let Some(TokenKind::Lang(PreToken::OP(op))) = get_next(token_stream) else {
+        return Err("Expected an operator".into());
+    };
+    match op {
+        Operator::ToFloat => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != Type::Int {
+                return Err(format!("Type mismatch, expected int, got {type1}",).into());
             }
-            Operator::Index => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                let type2 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if let Type::Array(t) = type1
-                    && type2 == Type::Int
-                {
-                    chunk.add_opcode(OpCode::Index);
-                    Ok(*t)
-                } else {
-                    Err("Type mismatch".to_string())
-                }
+            chunk.add_opcode(OpCode::ToFloat);
+            Ok(Type::Float)
+        }
+        Operator::ToInt => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if type1 != Type::Float {
+                return Err(format!("Type mismatch, expected float, got {type1}",).into());
             }
-            Operator::Length => {
-                token_stream.pop();
-                let type1 = consume_eval(
-                    chunk,
-                    token_stream,
-                    local_variables,
-                    function_signatures,
-                    constants,
-                )?;
-                if let Type::Array(_) = type1 {
-                    chunk.add_opcode(OpCode::LenArr);
-                    Ok(Type::Int)
-                } else if let Type::String = type1 {
-                    chunk.add_opcode(OpCode::LenStr);
-                    Ok(Type::Int)
-                } else {
-                    return Err("Type mismatch".to_string());
+            chunk.add_opcode(OpCode::ToInt);
+            Ok(Type::Int)
+        }
+        Operator::ToString => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            match type1 {
+                Type::Int | Type::Float | Type::Bool => {}
+                _ => {
+                    return Err(
+                        format!("Type mismatch, expected int, float or bool, got {type1}",).into(),
+                    );
                 }
             }
-            _ => todo!(),
-        },
-        _ => Err("Expected expression".to_string()),
+            chunk.add_opcode(OpCode::ToString);
+            Ok(Type::String)
+        }
+        _ => unreachable!("{op:?} is compiled elsewhere"),
+    }
+}
+
+/// Compiles `op`, one of the console operators, whose token is next.
+fn consume_console(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    depth: usize,
+) -> Result<Type, CompileError> {
+    let Some(TokenKind::Lang(PreToken::OP(op))) = get_next(token_stream) else {
+        return Err("Expected an operator".into());
+    };
+    match op {
+        Operator::Print | Operator::Println => {
+            token_stream.pop();
+            let type1 = consume_eval(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                depth,
+            )?;
+            if op == Operator::Print {
+                chunk.add_opcode(OpCode::Print);
+            } else {
+                chunk.add_opcode(OpCode::Println);
+            }
+            Ok(type1)
+        }
+        Operator::ReadLine => {
+            token_stream.pop();
+            chunk.add_opcode(OpCode::ReadLine);
+            Ok(Type::Array(Box::new(Type::String)))
+        }
+        Operator::ReadInt => {
+            token_stream.pop();
+            chunk.add_opcode(OpCode::ReadInt);
+            Ok(Type::Array(Box::new(Type::Int)))
+        }
+        _ => unreachable!("{op:?} is compiled elsewhere"),
+    }
+}
+
+/// Compiles a function body and checks that it evaluates to `t`.
+fn consume_body(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    t: &Type,
+) -> Result<(), CompileError> {
+    let body_span = get_span(token_stream);
+    let eval_type =
+        if consume_function_value(chunk, token_stream, local_variables, function_signatures, t)? {
+            t.clone()
+        } else {
+            consume_tail(
+                chunk,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                0,
+            )?
+        };
+    if *t != eval_type {
+        return Err(CompileError::new(
+            format!("Type mismatch, expected {t} got {eval_type}"),
+            body_span,
+        ));
+    }
+    Ok(())
+}
+
+/// Compiles guarded clauses `| cond => body`, tested in order and ending with
+/// a catch-all `| => body`. Each guard that fails jumps to the next clause,
+/// and each body jumps past the remaining clauses when it is done.
+fn consume_guards(
+    chunk: &mut Chunk,
+    token_stream: &mut Vec<Token>,
+    local_variables: &Vec<(String, Type)>,
+    function_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+    lambdas: &mut Vec<Chunk>,
+    t: &Type,
+) -> Result<(), CompileError> {
+    let mut clauses: Vec<(Chunk, Chunk)> = Vec::new();
+    let mut rest = loop {
+        if get_next(token_stream) != Some(TokenKind::Lang(PreToken::KW(Keyword::Bar))) {
+            return Err(CompileError::new(
+                "Expected a final | => clause".to_string(),
+                get_span(token_stream),
+            ));
+        }
+        token_stream.pop();
+        let otherwise =
+            get_next(token_stream) == Some(TokenKind::Lang(PreToken::KW(Keyword::Kerchow)));
+        let mut guard = Chunk::new(Vec::new());
+        if !otherwise {
+            let guard_span = get_span(token_stream);
+            let guard_type = consume_eval(
+                &mut guard,
+                token_stream,
+                local_variables,
+                function_signatures,
+                constants,
+                lambdas,
+                0,
+            )?;
+            if guard_type != Type::Bool {
+                return Err(CompileError::new(
+                    format!("Type mismatch, expected bool got {guard_type}"),
+                    guard_span,
+                ));
+            }
+            if get_next(token_stream) != Some(TokenKind::Lang(PreToken::KW(Keyword::Kerchow))) {
+                return Err(CompileError::new(
+                    "Expected kerchow".to_string(),
+                    get_span(token_stream),
+                ));
+            }
+        }
+        token_stream.pop();
+        let mut body = Chunk::new(Vec::new());
+        consume_body(
+            &mut body,
+            token_stream,
+            local_variables,
+            function_signatures,
+            constants,
+            lambdas,
+            t,
+        )?;
+        if otherwise {
+            break body;
+        }
+        clauses.push((guard, body));
+    };
+
+    // assemble from the last clause back, so every jump past the remaining
+    // clauses knows how far it goes
+    while let Some((mut guard, mut body)) = clauses.pop() {
+        let advance = operand_width(
+            OpCode::Advance,
+            OpCode::AdvanceLong,
+            &[rest.get_length()],
+            "Jump",
+        )?;
+        let skip = body.get_length() + 1 + advance.info().operands_size();
+        emit(
+            &mut guard,
+            OpCode::AdvanceIfFalse,
+            OpCode::AdvanceIfFalseLong,
+            &[skip],
+            "Jump",
+        )?;
+        guard.add_chunk(&mut body);
+        guard.add_instruction(advance, &[rest.get_length()]);
+        guard.add_chunk(&mut rest);
+        rest = guard;
     }
+    chunk.add_chunk(&mut rest);
+    Ok(())
 }
 
 fn consume_def(
     token_stream: &mut Vec<Token>,
-    func_signatures: &mut Vec<(String, Vec<Type>, Type)>,
-    constants: &mut Vec<Value>,
-) -> Result<(Option<Chunk>, bool), String> {
+    func_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+) -> Result<(Vec<Chunk>, bool), CompileError> {
     let mut chunk = Chunk::new(Vec::new());
+    let mut lambdas: Vec<Chunk> = Vec::new();
     let mut is_main = false;
 
     let mut local_variables: Vec<(String, Type)> = Vec::new();
 
     // consume name and fluf symbols
 
-    if get_next(token_stream) == Some(Token::Lang(PreToken::EOL)) {
+    if get_next(token_stream) == Some(TokenKind::Lang(PreToken::EOL)) {
         token_stream.pop();
-        return Ok((None, false));
+        return Ok((Vec::new(), false));
     }
+    let def_span = get_span(token_stream);
 
     let t = match get_next(token_stream) {
-        Some(Token::Lang(PreToken::TYPE(t))) => t,
+        Some(TokenKind::Lang(PreToken::TYPE(t))) => t,
         _ => {
-            return Err(format!(
-                "Expected type, got {:?}",
-                get_next(token_stream).unwrap()
+            return Err(CompileError::new(
+                format!("Expected type, got {:?}", get_next(token_stream)),
+                get_span(token_stream),
             ));
         }
     };
     token_stream.pop();
     let func_name = match get_next(token_stream) {
-        Some(Token::Symb(n)) => n.name(),
+        Some(TokenKind::Symb(n)) => n.name(),
         _ => {
-            return Err(format!(
-                "Expected function name, got {:?}",
-                get_next(token_stream)
+            return Err(CompileError::new(
+                format!("Expected function name, got {:?}", get_next(token_stream)),
+                get_span(token_stream),
             ));
         }
     };
@@ -626,93 +1854,219 @@ fn consume_def(
     if func_name == "main" {
         is_main = true;
     }
-    if get_next(token_stream) != Some(Token::Lang(PreToken::KW(Keyword::Define))) {
-        return Err("Expected define".to_string());
+    if get_next(token_stream) != Some(TokenKind::Lang(PreToken::KW(Keyword::Define))) {
+        return Err(CompileError::new(
+            "Expected define".to_string(),
+            get_span(token_stream),
+        ));
     }
     token_stream.pop();
 
     match get_sec_next(token_stream) {
-        Some(Token::Lang(PreToken::DEL(Delimeter::Colon))) => {
+        Some(TokenKind::Lang(PreToken::DEL(Delimeter::Colon))) => {
             // add local variables
             consume_function_args(token_stream, &mut local_variables)?;
 
-            if get_next(token_stream) != Some(Token::Lang(PreToken::KW(Keyword::Kerchow))) {
-                return Err("Expected kerchow".to_string());
-            }
-            token_stream.pop();
-
-            func_signatures.push((
+            func_signatures.push(FunctionSignature::new(
                 func_name.clone(),
                 local_variables.iter().map(|(_, lvt)| lvt.clone()).collect(),
                 t.clone(),
             ));
 
-            let eval_type = consume_eval(
-                &mut chunk,
-                token_stream,
-                &local_variables,
-                func_signatures,
-                constants,
-            )?;
-            if t != eval_type {
-                return Err(format!("Type mismatch, expected {t} got {eval_type}",));
+            if get_next(token_stream) == Some(TokenKind::Lang(PreToken::KW(Keyword::Bar))) {
+                consume_guards(
+                    &mut chunk,
+                    token_stream,
+                    &local_variables,
+                    func_signatures,
+                    constants,
+                    &mut lambdas,
+                    &t,
+                )?;
+            } else {
+                if get_next(token_stream) != Some(TokenKind::Lang(PreToken::KW(Keyword::Kerchow))) {
+                    return Err(CompileError::new(
+                        "Expected kerchow".to_string(),
+                        get_span(token_stream),
+                    ));
+                }
+                token_stream.pop();
+
+                consume_body(
+                    &mut chunk,
+                    token_stream,
+                    &local_variables,
+                    func_signatures,
+                    constants,
+                    &mut lambdas,
+                    &t,
+                )?;
             }
         }
         _ => {
-            func_signatures.push((func_name.clone(), Vec::new(), t.clone()));
-            let eval_type = consume_eval(
-                &mut chunk,
-                token_stream,
-                &local_variables,
-                func_signatures,
-                constants,
-            )?;
-            if t != eval_type {
-                return Err(format!("Type mismatch, expected {t} got {eval_type}",));
-            }
-        }
-    }
-
-    for (i, item) in local_variables.iter().enumerate() {
-        match item.1 {
-            Type::String => {
-                chunk.add_opcode(OpCode::DropLocalStr);
-                chunk.add_byte(i as u8);
-            }
-            Type::Array(_) => {
-                chunk.add_opcode(OpCode::DropLocalArr);
-                chunk.add_byte(i as u8);
-                chunk.add_byte(item.1.array_depth() as u8);
+            func_signatures.push(FunctionSignature::new(
+                func_name.clone(),
+                Vec::new(),
+                t.clone(),
+            ));
+            if get_next(token_stream) == Some(TokenKind::Lang(PreToken::KW(Keyword::Bar))) {
+                consume_guards(
+                    &mut chunk,
+                    token_stream,
+                    &local_variables,
+                    func_signatures,
+                    constants,
+                    &mut lambdas,
+                    &t,
+                )?;
+            } else {
+                // an explicit empty parameter list, so the body can be a lambda
+                if get_next(token_stream) == Some(TokenKind::Lang(PreToken::KW(Keyword::Kerchow))) {
+                    token_stream.pop();
+                }
+                consume_body(
+                    &mut chunk,
+                    token_stream,
+                    &local_variables,
+                    func_signatures,
+                    constants,
+                    &mut lambdas,
+                    &t,
+                )?;
             }
-            _ => {}
         }
     }
 
     chunk.add_opcode(OpCode::Return);
-    Ok((Some(chunk), is_main))
+    chunk.mark_positions(0, &def_span);
+
+    // lambdas got the signatures right after this function's
+    let mut chunks = vec![chunk];
+    chunks.append(&mut lambdas);
+    Ok((chunks, is_main))
 }
 
+/// Chunks of the compiled functions, plus the function index and type of main
+/// if it was defined.
+pub type CompiledProgram = (Vec<Chunk>, Option<usize>, Option<Type>);
+
 pub fn compile(
     token_stream: &mut Vec<Token>,
-    func_signatures: &mut Vec<(String, Vec<Type>, Type)>,
-    constants: &mut Vec<Value>,
-) -> Result<(Vec<Chunk>, Option<usize>, Option<Type>), String> {
+    func_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+) -> Result<CompiledProgram, CompileError> {
     let mut chunks: Vec<Chunk> = Vec::new();
     let mut main: Option<usize> = None;
     let mut main_type: Option<Type> = None;
-    let mut i = 0;
+    let mut i = func_signatures.len();
     while !token_stream.is_empty() {
-        let (chunk, is_main) = consume_def(token_stream, func_signatures, constants)?;
-        if chunk.is_none() {
+        let (mut def_chunks, is_main) = consume_def(token_stream, func_signatures, constants)?;
+        if def_chunks.is_empty() {
             continue;
         }
-        let chunk = chunk.unwrap();
-        chunks.push(chunk);
         if is_main {
             main = Some(i);
-            main_type = Some(func_signatures[i].2.clone());
+            main_type = Some(func_signatures[i].ret.clone());
         }
-        i += 1;
+        i += def_chunks.len();
+        chunks.append(&mut def_chunks);
     }
     Ok((chunks, main, main_type))
 }
+
+/// Compiles a single expression, which may call any function in
+/// `func_signatures`, into a new function without parameters. Returns its
+/// chunk, followed by those of any lambdas in it, and the expression's type.
+pub fn compile_expression(
+    token_stream: &mut Vec<Token>,
+    func_signatures: &mut Vec<FunctionSignature>,
+    constants: &mut Vec<Literal>,
+) -> Result<(Vec<Chunk>, Type), CompileError> {
+    let span = get_span(token_stream);
+    let mut chunk = Chunk::new(Vec::new());
+    let mut lambdas: Vec<Chunk> = Vec::new();
+    let index = func_signatures.len();
+    func_signatures.push(FunctionSignature::new(
+        "<eval>".to_string(),
+        Vec::new(),
+        Type::AnyType,
+    ));
+    let t = consume_tail(
+        &mut chunk,
+        token_stream,
+        &Vec::new(),
+        func_signatures,
+        constants,
+        &mut lambdas,
+        0,
+    )?;
+    while get_next(token_stream) == Some(TokenKind::Lang(PreToken::EOL)) {
+        token_stream.pop();
+    }
+    if !token_stream.is_empty() {
+        return Err(CompileError::new(
+            "Expected the end of the expression".to_string(),
+            get_span(token_stream),
+        ));
+    }
+    func_signatures[index].ret = t.clone();
+    chunk.add_opcode(OpCode::Return);
+    chunk.mark_positions(0, &span);
+
+    let mut chunks = vec![chunk];
+    chunks.append(&mut lambdas);
+    Ok((chunks, t))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    /// Compiles `source` and returns the signatures of its functions.
+    fn signatures(source: &str) -> Vec<FunctionSignature> {
+        let file: Rc<str> = "<source>".into();
+        let mut tokens = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            parse_line(line, &file, i + 1, &mut tokens).unwrap();
+        }
+        tokens.reverse();
+        let mut signatures = Vec::new();
+        compile(&mut tokens, &mut signatures, &mut Vec::new()).unwrap();
+        signatures
+    }
+
+    #[test]
+    fn lambdas_capture_the_locals_they_use() {
+        let source = "int main := punch a := [1] punch g := x : int => x ; + g 1 len a";
+        assert_eq!(signatures(source)[1].params, [Type::Int]);
+        let source =
+            "int main := punch a := [1] punch n := 2 punch g := x : int => + n len a ; g 1";
+        assert_eq!(
+            signatures(source)[1].params,
+            [Type::Array(Box::new(Type::Int)), Type::Int, Type::Int]
+        );
+        // a body's own bindings are not captures, even when they shadow one
+        let source = "int main := punch a := 1 punch g := x : int => punch a := x a ; g a";
+        assert_eq!(signatures(source)[1].params, [Type::Int]);
+    }
+
+    #[test]
+    fn operand_widths() {
+        let width = |operand| {
+            operand_width(
+                OpCode::Constant,
+                OpCode::ConstantLong,
+                &[operand],
+                "Constant index",
+            )
+        };
+        assert_eq!(width(255).unwrap(), OpCode::Constant);
+        assert_eq!(width(256).unwrap(), OpCode::ConstantLong);
+        assert_eq!(width(u32::MAX as usize).unwrap(), OpCode::ConstantLong);
+        assert_eq!(
+            width(1 << 32).unwrap_err().message,
+            "Constant index is too large to encode"
+        );
+    }
+}
@@ -0,0 +1,274 @@
+use crate::chunk::Chunk;
+use crate::compiler::{CompileError, compile, compile_expression};
+use crate::parser::{Literal, ParsingError, Token, parse_line};
+use crate::prelude;
+use crate::tokenizer::Scanner;
+use crate::value::{BreakoutValue, FunctionSignature, Type, Value};
+use crate::verifier::VerifyError;
+use crate::vm::{RuntimeError, VM};
+use std::error::Error;
+use std::fmt;
+use std::rc::Rc;
+
+#[derive(Debug)]
+pub enum EngineError {
+    Io(std::io::Error),
+    Parse(ParsingError),
+    Compile(CompileError),
+    Verify(VerifyError),
+    Runtime(RuntimeError),
+    /// No loaded function has this name.
+    UnknownFunction(String),
+    /// The arguments passed to `Engine::call` do not match the signature.
+    InvalidArguments(String),
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::Io(e) => write!(f, "{e}"),
+            EngineError::Parse(e) => write!(f, "{e}"),
+            EngineError::Compile(e) => write!(f, "{e}"),
+            EngineError::Verify(e) => write!(f, "{e}"),
+            EngineError::Runtime(e) => write!(f, "{e}"),
+            EngineError::UnknownFunction(name) => write!(f, "Unknown function {name}"),
+            EngineError::InvalidArguments(message) => write!(f, "{message}"),
+        }
+    }
+}
+impl Error for EngineError {}
+
+impl From<std::io::Error> for EngineError {
+    fn from(e: std::io::Error) -> Self {
+        EngineError::Io(e)
+    }
+}
+impl From<ParsingError> for EngineError {
+    fn from(e: ParsingError) -> Self {
+        EngineError::Parse(e)
+    }
+}
+impl From<CompileError> for EngineError {
+    fn from(e: CompileError) -> Self {
+        EngineError::Compile(e)
+    }
+}
+impl From<VerifyError> for EngineError {
+    fn from(e: VerifyError) -> Self {
+        EngineError::Verify(e)
+    }
+}
+impl From<RuntimeError> for EngineError {
+    fn from(e: RuntimeError) -> Self {
+        EngineError::Runtime(e)
+    }
+}
+
+/// Loads Breakout definitions and calls into them. Every load adds to the
+/// functions already loaded, so later sources can call earlier ones.
+pub struct Engine {
+    vm: VM,
+    function_signatures: Vec<FunctionSignature>,
+    constants: Vec<Literal>,
+    fuel: u64,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Engine::new()
+    }
+}
+
+impl Engine {
+    /// An engine with only the prelude loaded.
+    pub fn new() -> Engine {
+        let mut engine = Engine {
+            vm: VM::new(),
+            function_signatures: Vec::new(),
+            constants: Vec::new(),
+            fuel: u64::MAX,
+        };
+        prelude::load(&mut engine.vm, &mut engine.function_signatures);
+        engine
+    }
+    /// Sets how much fuel each call or eval may use before it fails with
+    /// `OutOfFuel`, as counted by `VM::fuel_used`. Unlimited by
+    /// default.
+    pub fn set_fuel(&mut self, fuel: u64) {
+        self.fuel = fuel;
+    }
+    /// The VM that runs the loaded code, for setting its limits.
+    pub fn vm_mut(&mut self) -> &mut VM {
+        &mut self.vm
+    }
+    /// Makes the host function `native` callable from Breakout as `name`,
+    /// type checked against `params` and `ret` like any other function. A
+    /// call that returns a value of another type fails with `NativeFailed`.
+    /// Like definitions, natives can only be used by code loaded after them.
+    pub fn register_native(
+        &mut self,
+        name: &str,
+        params: Vec<Type>,
+        ret: Type,
+        native: impl Fn(&[BreakoutValue]) -> Result<BreakoutValue, String> + 'static,
+    ) {
+        self.function_signatures.push(FunctionSignature {
+            name: name.to_string(),
+            params,
+            ret,
+            native: true,
+        });
+        self.vm.give_native(Rc::new(move |args: &[Value]| {
+            let args: Vec<BreakoutValue> = args.iter().map(BreakoutValue::from).collect();
+            native(&args).map(|value| Value::from(&value))
+        }));
+        self.vm
+            .update_function_signatures(&self.function_signatures);
+    }
+    /// Compiles and loads the definitions in `source`.
+    pub fn load_source(&mut self, source: &str) -> Result<(), EngineError> {
+        let file: Rc<str> = "<source>".into();
+        let mut tokens: Vec<Token> = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            parse_line(line, &file, i + 1, &mut tokens)?;
+        }
+        tokens.reverse();
+        self.load_tokens(tokens)
+    }
+    /// Compiles and loads the definitions in the file at `path`, along with
+    /// the files it includes.
+    pub fn load_file(&mut self, path: &str) -> Result<(), EngineError> {
+        let mut scanner = Scanner::new();
+        scanner.load_file(path)?;
+        let mut tokens: Vec<Token> = Vec::new();
+        while let Some((file, line_number, line)) = scanner.get_next_line() {
+            parse_line(&line, &file, line_number, &mut tokens)?;
+        }
+        tokens.reverse();
+        self.load_tokens(tokens)
+    }
+    fn load_tokens(&mut self, mut tokens: Vec<Token>) -> Result<(), EngineError> {
+        let functions = self.function_signatures.len();
+        let constants = self.constants.len();
+        let loaded = compile(
+            &mut tokens,
+            &mut self.function_signatures,
+            &mut self.constants,
+        )
+        .map_err(EngineError::from)
+        .and_then(|(chunks, _, _)| self.give_chunks(chunks));
+        if loaded.is_err() {
+            // leave the engine as it was before the load
+            self.function_signatures.truncate(functions);
+            self.constants.truncate(constants);
+            self.vm.truncate_functions(functions);
+        }
+        loaded
+    }
+    fn give_chunks(&mut self, chunks: Vec<Chunk>) -> Result<(), EngineError> {
+        for chunk in chunks {
+            self.vm.give_data(chunk);
+        }
+        self.vm.update_constants(&self.constants);
+        self.vm
+            .update_function_signatures(&self.function_signatures);
+        self.vm.verify()?;
+        Ok(())
+    }
+    /// Calls the loaded function `name` with `args`, which must match its
+    /// parameter types.
+    pub fn call(
+        &mut self,
+        name: &str,
+        args: &[BreakoutValue],
+    ) -> Result<BreakoutValue, EngineError> {
+        let function = self
+            .function_signatures
+            .iter()
+            .rposition(|f| f.name == name)
+            .ok_or_else(|| EngineError::UnknownFunction(name.to_string()))?;
+        let params = &self.function_signatures[function].params;
+        if params.len() != args.len() {
+            return Err(EngineError::InvalidArguments(format!(
+                "{name} takes {} arguments, called with {}",
+                params.len(),
+                args.len()
+            )));
+        }
+        let args: Vec<Value> = args.iter().map(Value::from).collect();
+        for (i, (arg, param)) in args.iter().zip(params).enumerate() {
+            if !self.vm.has_type(arg, param) {
+                return Err(EngineError::InvalidArguments(format!(
+                    "Argument {} of {name} should be {param}, got {}",
+                    i + 1,
+                    BreakoutValue::from(arg)
+                )));
+            }
+        }
+        Ok(self.vm.call_function(function, args, self.fuel)?)
+    }
+    /// Evaluates a single expression, which may call any loaded function.
+    pub fn eval(&mut self, expression: &str) -> Result<BreakoutValue, EngineError> {
+        let file: Rc<str> = "<eval>".into();
+        let mut tokens: Vec<Token> = Vec::new();
+        for (i, line) in expression.lines().enumerate() {
+            parse_line(line, &file, i + 1, &mut tokens)?;
+        }
+        tokens.reverse();
+        let functions = self.function_signatures.len();
+        let constants = self.constants.len();
+        let result = compile_expression(
+            &mut tokens,
+            &mut self.function_signatures,
+            &mut self.constants,
+        )
+        .map_err(EngineError::from)
+        .and_then(|(chunks, _)| self.give_chunks(chunks))
+        .and_then(|()| Ok(self.vm.call_function(functions, Vec::new(), self.fuel)?));
+        // the expression's functions are only needed for this one run, unless
+        // it returned one of its lambdas
+        if !result
+            .as_ref()
+            .is_ok_and(|value| calls_from(value, functions))
+        {
+            self.function_signatures.truncate(functions);
+            self.constants.truncate(constants);
+            self.vm.truncate_functions(functions);
+        }
+        result
+    }
+}
+
+/// Whether `value` holds a function with an index of `first` or more.
+fn calls_from(value: &BreakoutValue, first: usize) -> bool {
+    // captures can nest arbitrarily deep, so walk them with explicit stacks
+    let mut outer = vec![value];
+    let mut captured: Vec<&Value> = Vec::new();
+    loop {
+        if let Some(value) = outer.pop() {
+            match value {
+                BreakoutValue::Function(function) => {
+                    if function.function() >= first {
+                        return true;
+                    }
+                    captured.extend(&function.0.captures);
+                }
+                BreakoutValue::Array(items) => outer.extend(items),
+                _ => {}
+            }
+        } else if let Some(value) = captured.pop() {
+            match value {
+                Value::Fn(closure) => {
+                    if closure.function >= first {
+                        return true;
+                    }
+                    captured.extend(&closure.captures);
+                }
+                Value::Arr(items) => captured.extend(items.iter()),
+                _ => {}
+            }
+        } else {
+            return false;
+        }
+    }
+}
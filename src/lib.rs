@@ -0,0 +1,18 @@
+//! Byte code interpreter for Breakout. [`Engine`] loads Breakout source and
+//! calls into it; the modules expose each stage on its own for tools such as
+//! the command line interpreter.
+
+pub mod bytecode;
+pub mod chunk;
+pub mod common;
+pub mod compiler;
+pub mod engine;
+pub mod parser;
+pub mod prelude;
+pub mod tokenizer;
+pub mod value;
+pub mod verifier;
+pub mod vm;
+
+pub use engine::{Engine, EngineError};
+pub use value::BreakoutValue;
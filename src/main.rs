@@ -1,79 +1,265 @@
-#![feature(thin_box)]
-mod chunk;
-mod common;
-mod compiler;
-mod parser;
-mod tokenizer;
-mod value;
-mod vm;
-
-use crate::parser::Token;
-use compiler::compile;
-use parser::parse;
-use parser::parse_line;
+use bytecodeinterpreter::bytecode::BytecodeProgram;
+use bytecodeinterpreter::chunk::Chunk;
+use bytecodeinterpreter::compiler::compile;
+use bytecodeinterpreter::parser::{Literal, Token, parse, parse_line};
+use bytecodeinterpreter::prelude;
+use bytecodeinterpreter::value::{BreakoutValue, FunctionSignature};
+use bytecodeinterpreter::vm::{
+    DEFAULT_MAX_ARRAY_LEN, DEFAULT_MAX_CALL_DEPTH, DEFAULT_MAX_STACK_SIZE, RuntimeError, VM,
+};
 use std::env;
-use std::io::stdin;
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write, stdin};
+use std::path::Path;
+use std::process::ExitCode;
+use std::rc::Rc;
 use std::time::SystemTime;
-use vm::VM;
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = env::args().collect();
-    let path = args.get(1);
+fn main() -> ExitCode {
+    match run_cli() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    let mut function_signatures = Vec::new();
+const USAGE: &str = "Usage:
+\tbytecodeinterpreter                                    start a REPL
+\tbytecodeinterpreter <file.bo>                          compile and run a program
+\tbytecodeinterpreter compile <file.bo> [-o <out.bbc>]   write the compiled program
+\tbytecodeinterpreter run <file.bbc>                     run a compiled program
+
+Pass --disasm to print the bytecode instead of running it.
+Pass --max-call-depth=<n> or --max-stack-size=<n> to change when deep
+recursion fails with a stack overflow, --max-array-len=<n> to limit the arrays
+and strings a program builds, and --fuel=<n> to stop a program after n
+instructions.";
+
+/// How deep a program may recurse before it fails with a stack overflow, how
+/// long its arrays may grow and how many instructions it may run.
+struct Limits {
+    max_call_depth: usize,
+    max_stack_size: usize,
+    max_array_len: usize,
+    fuel: Option<u64>,
+}
+
+impl Limits {
+    fn apply(&self, vm: &mut VM) {
+        vm.set_max_call_depth(self.max_call_depth);
+        vm.set_max_stack_size(self.max_stack_size);
+        vm.set_max_array_len(self.max_array_len);
+    }
+    fn run(&self, vm: &mut VM) -> Result<BreakoutValue, RuntimeError> {
+        match self.fuel {
+            Some(fuel) => vm.run_with_budget(fuel),
+            None => vm.run(),
+        }
+    }
+}
+
+/// Reads the value of `--name=<n>` from `args`, if it is there.
+fn numeric_option<T: std::str::FromStr>(
+    args: &[String],
+    name: &str,
+) -> Result<Option<T>, Box<dyn Error>> {
+    let prefix = format!("--{name}=");
+    match args.iter().rev().find_map(|a| a.strip_prefix(&prefix)) {
+        Some(value) => match value.parse() {
+            Ok(n) => Ok(Some(n)),
+            Err(_) => Err(format!("Invalid value for --{name}: {value}").into()),
+        },
+        None => Ok(None),
+    }
+}
+
+fn run_cli() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let disasm = args.iter().any(|a| a == "--disasm");
+    let limits = Limits {
+        max_call_depth: numeric_option(&args, "max-call-depth")?.unwrap_or(DEFAULT_MAX_CALL_DEPTH),
+        max_stack_size: numeric_option(&args, "max-stack-size")?.unwrap_or(DEFAULT_MAX_STACK_SIZE),
+        max_array_len: numeric_option(&args, "max-array-len")?.unwrap_or(DEFAULT_MAX_ARRAY_LEN),
+        fuel: numeric_option(&args, "fuel")?,
+    };
+    let args: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .filter(|a| {
+            *a != "--disasm"
+                && !a.starts_with("--max-call-depth=")
+                && !a.starts_with("--max-stack-size=")
+                && !a.starts_with("--max-array-len=")
+                && !a.starts_with("--fuel=")
+        })
+        .collect();
+    match args.as_slice() {
+        [] => repl(disasm, &limits),
+        ["compile", source] => {
+            let out = Path::new(source).with_extension("bbc");
+            compile_to_file(source, &out)
+        }
+        ["compile", source, "-o", out] => compile_to_file(source, Path::new(out)),
+        ["run", path] => {
+            let program = BytecodeProgram::read(&mut File::open(path)?)?;
+            if disasm {
+                print_disassembly(
+                    &program.chunks,
+                    &program.constants,
+                    &program.function_signatures,
+                    0,
+                );
+                return Ok(());
+            }
+            execute(program, &limits)
+        }
+        [path] if !path.starts_with('-') && *path != "compile" && *path != "run" => {
+            let program = compile_file(path)?;
+            if disasm {
+                print_disassembly(
+                    &program.chunks,
+                    &program.constants,
+                    &program.function_signatures,
+                    0,
+                );
+                return Ok(());
+            }
+            execute(program, &limits)
+        }
+        _ => Err(USAGE.into()),
+    }
+}
+
+fn compile_file(path: &str) -> Result<BytecodeProgram, Box<dyn Error>> {
+    let mut function_signatures = prelude::signatures();
     let mut constants = Vec::new();
     let mut tokens: Vec<Token> = Vec::new();
-    let mut vm = VM::new();
+    parse(path, &mut tokens)?;
+    let (chunks, main_loc, main_type) =
+        compile(&mut tokens, &mut function_signatures, &mut constants)?;
+    Ok(BytecodeProgram {
+        chunks,
+        constants,
+        function_signatures,
+        main: main_loc.zip(main_type),
+    })
+}
 
-    if path.is_some() {
-        // file read mode
-        parse(path.unwrap(), &mut tokens)?;
-        let (chunks, main_loc, main_type) =
-            compile(&mut tokens, &mut function_signatures, &mut constants)?;
+fn compile_to_file(source: &str, out: &Path) -> Result<(), Box<dyn Error>> {
+    let program = compile_file(source)?;
+    let mut file = BufWriter::new(File::create(out)?);
+    program.write(&mut file)?;
+    file.flush()?;
+    Ok(())
+}
 
-        for chunk in chunks {
-            println!("{:?}", chunk);
+fn execute(program: BytecodeProgram, limits: &Limits) -> Result<(), Box<dyn Error>> {
+    let (main_loc, _) = program.main.ok_or("No main function defined")?;
+    let mut vm = VM::new();
+    limits.apply(&mut vm);
+    // natives have no chunk in the program, so link them back in by signature
+    let mut chunks = program.chunks.into_iter();
+    for signature in &program.function_signatures {
+        if signature.native {
+            let native = prelude::find(signature)
+                .ok_or_else(|| format!("Unknown native function {}", signature.name))?;
+            vm.give_native(native);
+        } else {
+            let chunk = chunks.next().ok_or("Fewer chunks than functions")?;
             vm.give_data(chunk);
         }
-        vm.update_constants(&constants);
-        vm.set_main(main_loc.unwrap(), main_type.unwrap());
+    }
+    if chunks.next().is_some() {
+        return Err("More chunks than functions".into());
+    }
+    vm.update_constants(&program.constants);
+    vm.update_function_signatures(&program.function_signatures);
+    vm.set_main(main_loc);
+    vm.verify()?;
+
+    println!("Executing");
+    let exec_start = SystemTime::now();
+    let result = limits.run(&mut vm)?;
+    let exec_end = SystemTime::now();
+    println!("{result}\n");
+    println!(
+        "\nExecuted {} instructions in: {}ms\n",
+        vm.instruction_count(),
+        exec_end.duration_since(exec_start).unwrap().as_millis()
+    );
+    Ok(())
+}
 
-        println!("Executing");
-        let exec_start = SystemTime::now();
-        vm.run()?;
-        let exec_end = SystemTime::now();
+/// Prints `chunks`, which belong to the functions from number `first_index`
+/// on that are not natives.
+fn print_disassembly(
+    chunks: &[Chunk],
+    constants: &[Literal],
+    function_signatures: &[FunctionSignature],
+    first_index: usize,
+) {
+    let functions = function_signatures[first_index..]
+        .iter()
+        .filter(|f| !f.native);
+    for (chunk, function) in chunks.iter().zip(functions) {
+        let name = &function.name;
         println!(
-            "\nExecuted in: {}ms\n",
-            exec_end.duration_since(exec_start).unwrap().as_millis()
+            "{}",
+            chunk.disassemble(name, constants, function_signatures)
         );
-    } else {
-        let mut buffer = String::new();
-        let mut next = String::new();
-        loop {
-            if next.trim().is_empty() && !buffer.trim().is_empty() {
-                parse_line(&buffer, &mut tokens)?;
-                tokens.reverse();
-                buffer = String::new();
-                next = String::new();
-                let (chunks, main_loc, main_type) =
-                    compile(&mut tokens, &mut function_signatures, &mut constants)?;
+    }
+}
 
-                for chunk in chunks {
-                    vm.give_data(chunk);
-                }
-                vm.update_constants(&constants);
-                if main_loc.is_some() {
-                    vm.set_main(main_loc.unwrap(), main_type.unwrap());
-                    vm.run()?;
-                }
-            } else {
-                buffer.push_str(&next);
-                next = String::new();
-                stdin().read_line(&mut next).unwrap();
-                if next == "exit\n" {
-                    break;
+fn repl(disasm: bool, limits: &Limits) -> Result<(), Box<dyn Error>> {
+    let mut function_signatures = Vec::new();
+    let mut constants = Vec::new();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut vm = VM::new();
+    limits.apply(&mut vm);
+    prelude::load(&mut vm, &mut function_signatures);
+    let stdin_name: Rc<str> = "<stdin>".into();
+    let mut buffer = String::new();
+    let mut next = String::new();
+    loop {
+        if next.trim().is_empty() && !buffer.trim().is_empty() {
+            for (i, line) in buffer.lines().enumerate() {
+                parse_line(line, &stdin_name, i + 1, &mut tokens)?;
+            }
+            tokens.reverse();
+            buffer = String::new();
+            next = String::new();
+            let first_index = function_signatures.len();
+            let (chunks, main_loc, _) =
+                compile(&mut tokens, &mut function_signatures, &mut constants)?;
+
+            if disasm {
+                print_disassembly(&chunks, &constants, &function_signatures, first_index);
+            }
+            for chunk in chunks {
+                vm.give_data(chunk);
+            }
+            vm.update_constants(&constants);
+            vm.update_function_signatures(&function_signatures);
+            if let Some(main_loc) = main_loc {
+                vm.set_main(main_loc);
+            }
+            vm.verify()?;
+            if main_loc.is_some() {
+                match limits.run(&mut vm) {
+                    Ok(result) => println!("{result}\n"),
+                    Err(e) => eprintln!("{e}"),
                 }
             }
+        } else {
+            buffer.push_str(&next);
+            next = String::new();
+            stdin().read_line(&mut next).unwrap();
+            if next == "exit\n" {
+                break;
+            }
         }
     }
 
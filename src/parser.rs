@@ -4,6 +4,7 @@ use crate::value::Type;
 use ordered_float::OrderedFloat;
 use std::error::Error;
 use std::fmt;
+use std::rc::Rc;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Symbol {
@@ -54,48 +55,64 @@ impl std::fmt::Display for Literal {
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Token {
+pub enum TokenKind {
     Lang(PreToken),
     Symb(Symbol),
     Lit(Literal),
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
 #[derive(Debug, Clone)]
 pub struct ParsingError {
-    pub line: usize,
+    pub span: Span,
     pub message: String,
 }
 
 impl fmt::Display for ParsingError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Error on line {}:\n\t{}", self.line, self.message)
+        writeln!(f, "Error at {}:\n\t{}", self.span, self.message)?;
+        self.span.fmt_source(f)
     }
 }
 impl Error for ParsingError {}
 
-fn parse_literal(s: String) -> Result<Token, ParsingError> {
-    if s.starts_with("'") && s.ends_with("'") {
-        Ok(Token::Lit(Literal::String(s[1..s.len() - 1].to_owned())))
+fn parse_literal(s: String, span: &Span) -> Result<TokenKind, ParsingError> {
+    if s.len() >= 2
+        && ((s.starts_with('"') && s.ends_with('"')) || (s.starts_with('\'') && s.ends_with('\'')))
+    {
+        Ok(TokenKind::Lit(Literal::String(
+            s[1..s.len() - 1].to_owned(),
+        )))
     } else if s.parse::<i64>().is_ok() {
-        Ok(Token::Lit(Literal::Integer(s.parse().unwrap())))
+        Ok(TokenKind::Lit(Literal::Integer(s.parse().unwrap())))
     } else if s.parse::<f64>().is_ok() {
-        Ok(Token::Lit(Literal::Float(OrderedFloat(s.parse().unwrap()))))
+        Ok(TokenKind::Lit(Literal::Float(OrderedFloat(
+            s.parse().unwrap(),
+        ))))
     } else if s == "true" || s == "false" {
         if s == "true" {
-            Ok(Token::Lit(Literal::Bool(true)))
+            Ok(TokenKind::Lit(Literal::Bool(true)))
         } else {
-            Ok(Token::Lit(Literal::Bool(false)))
+            Ok(TokenKind::Lit(Literal::Bool(false)))
         }
     } else {
-        panic!("where is my literal??")
+        Err(ParsingError {
+            span: span.clone(),
+            message: format!("Invalid literal {s}"),
+        })
     }
 }
 
-fn parse_symbol(s: String) -> Result<Token, ParsingError> {
-    Ok(Token::Symb(Symbol::new(s)))
+fn parse_symbol(s: String) -> Result<TokenKind, ParsingError> {
+    Ok(TokenKind::Symb(Symbol::new(s)))
 }
 
-fn parse_word(s: String) -> Result<Token, ParsingError> {
+fn parse_word(s: String, span: &Span) -> Result<TokenKind, ParsingError> {
     if s.starts_with("\"")
         || s.starts_with("0")
         || s.starts_with("1")
@@ -111,29 +128,25 @@ fn parse_word(s: String) -> Result<Token, ParsingError> {
         || s == "true"
         || s == "false"
     {
-        parse_literal(s)
+        parse_literal(s, span)
     } else {
-        Ok(Token::Symb(Symbol::new(s)))
+        Ok(TokenKind::Symb(Symbol::new(s)))
     }
 }
 
-fn collapse_array_types(tokens: Vec<PreTokenized>) -> Vec<PreTokenized> {
-    let mut out: Vec<PreTokenized> = Vec::new();
+fn collapse_array_types(
+    tokens: Vec<(PreTokenized, Span)>,
+) -> Result<Vec<(PreTokenized, Span)>, ParsingError> {
+    let mut out: Vec<(PreTokenized, Span)> = Vec::new();
     let mut i: usize = 0;
-    let mut check = true;
     while i < tokens.len() {
-        match tokens[i] {
+        match tokens[i].0 {
             PreTokenized::T(PreToken::DEL(Delimeter::LBracket)) => {
-                if !check {
-                    out.push(tokens[i].clone());
-                    i += 1;
-                    continue;
-                }
                 let mut count: isize = 1;
                 let mut max: usize = 1;
                 let mut j = i + 1;
                 while (count != 0) && j < tokens.len() {
-                    match tokens[j] {
+                    match tokens[j].0 {
                         PreTokenized::T(PreToken::DEL(Delimeter::LBracket)) => {
                             count += 1;
                             max += 1;
@@ -151,12 +164,15 @@ fn collapse_array_types(tokens: Vec<PreTokenized>) -> Vec<PreTokenized> {
                     out.push(tokens[i].clone());
                     i += 1;
                 } else if count == 0 {
-                    if let PreTokenized::T(PreToken::TYPE(t)) = &tokens[i + max] {
+                    if let PreTokenized::T(PreToken::TYPE(t)) = &tokens[i + max].0 {
                         let mut arr_type = t.clone();
                         for _ in 0..max {
                             arr_type = Type::Array(Box::new(arr_type));
                         }
-                        out.push(PreTokenized::T(PreToken::TYPE(arr_type)));
+                        out.push((
+                            PreTokenized::T(PreToken::TYPE(arr_type)),
+                            tokens[i].1.clone(),
+                        ));
                         i = j;
                     } else {
                         out.push(tokens[i].clone());
@@ -166,46 +182,118 @@ fn collapse_array_types(tokens: Vec<PreTokenized>) -> Vec<PreTokenized> {
                     out.push(tokens[i].clone());
                     i += 1;
                 } else {
-                    panic!("unclosed array");
+                    return Err(ParsingError {
+                        span: tokens[i].1.clone(),
+                        message: "Unclosed array".to_string(),
+                    });
                 }
             }
-            PreTokenized::T(PreToken::EOL) => {
-                check = true;
+            _ => {
                 out.push(tokens[i].clone());
                 i += 1;
             }
-            PreTokenized::T(PreToken::KW(Keyword::Kerchow)) => {
-                check = false;
-                out.push(tokens[i].clone());
-                i += 1;
+        }
+    }
+    Ok(out)
+}
+
+/// Replaces each parenthesised group of parameter types, `->` and a return
+/// type with a single function type token. Inner groups are collapsed before
+/// the groups around them.
+fn collapse_function_types(tokens: Vec<(PreTokenized, Span)>) -> Vec<(PreTokenized, Span)> {
+    let mut out: Vec<(PreTokenized, Span)> = Vec::new();
+    let mut open: Vec<usize> = Vec::new();
+    for token in tokens {
+        match token.0 {
+            PreTokenized::T(PreToken::DEL(Delimeter::LPar)) => {
+                open.push(out.len());
+                out.push(token);
             }
-            _ => {
-                out.push(tokens[i].clone());
-                i += 1;
+            PreTokenized::T(PreToken::DEL(Delimeter::RPar)) => {
+                let Some(start) = open.pop() else {
+                    out.push(token);
+                    continue;
+                };
+                let mut types = Vec::new();
+                let mut arrow = None;
+                let mut is_type = true;
+                for (i, (inner, _)) in out[start + 1..].iter().enumerate() {
+                    match inner {
+                        PreTokenized::T(PreToken::TYPE(t)) => types.push(t.clone()),
+                        PreTokenized::T(PreToken::DEL(Delimeter::Arrow)) if arrow.is_none() => {
+                            arrow = Some(i)
+                        }
+                        _ => is_type = false,
+                    }
+                }
+                match arrow {
+                    Some(arrow) if is_type && types.len() == arrow + 1 => {
+                        let ret = types.pop().unwrap();
+                        let span = out[start].1.clone();
+                        out.truncate(start);
+                        out.push((
+                            PreTokenized::T(PreToken::TYPE(Type::Function(types, Box::new(ret)))),
+                            span,
+                        ));
+                    }
+                    _ => out.push(token),
+                }
             }
+            _ => out.push(token),
         }
     }
     out
 }
 
-pub fn parse_line(line: &str, out: &mut Vec<Token>) -> Result<(), ParsingError> {
-    let pre_tokens = collapse_array_types(tokenize_line(line.to_string()));
-    for token in pre_tokens.iter() {
-        match token {
-            PreTokenized::T(t) => out.push(Token::Lang(t.clone())),
-            PreTokenized::S(s) => {
-                out.push(parse_word(s.to_string())?);
-            }
+pub fn parse_line(
+    line: &str,
+    file: &Rc<str>,
+    line_number: usize,
+    out: &mut Vec<Token>,
+) -> Result<(), ParsingError> {
+    let source: Rc<str> = line.into();
+    let spanned = tokenize_line(line)
+        .into_iter()
+        .map(|(t, column)| {
+            let span = Span {
+                file: file.clone(),
+                line: line_number,
+                column,
+                source: source.clone(),
+            };
+            (t, span)
+        })
+        .collect();
+    // array and function types can nest inside each other, so collapse both
+    // until nothing changes
+    let mut pre_tokens: Vec<(PreTokenized, Span)> = spanned;
+    loop {
+        let len = pre_tokens.len();
+        pre_tokens = collapse_function_types(collapse_array_types(pre_tokens)?);
+        if pre_tokens.len() == len {
+            break;
         }
     }
+    // any other parentheses only group expressions visually
+    pre_tokens.retain(|(t, _)| {
+        t != &PreTokenized::T(PreToken::DEL(Delimeter::LPar))
+            && t != &PreTokenized::T(PreToken::DEL(Delimeter::RPar))
+    });
+    for (token, span) in pre_tokens.into_iter() {
+        let kind = match token {
+            PreTokenized::T(t) => TokenKind::Lang(t),
+            PreTokenized::S(s) => parse_word(s, &span)?,
+        };
+        out.push(Token { kind, span });
+    }
     Ok(())
 }
 
 pub fn parse(path: &str, out: &mut Vec<Token>) -> Result<(), Box<dyn Error>> {
     let mut scanner = Scanner::new();
     scanner.load_file(path)?;
-    while let Some(line) = scanner.get_next_line() {
-        parse_line(&line, out)?;
+    while let Some((file, line_number, line)) = scanner.get_next_line() {
+        parse_line(&line, &file, line_number, out)?;
     }
     out.reverse();
     Ok(())
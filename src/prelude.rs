@@ -0,0 +1,221 @@
+//! Native functions that every program can call without defining them.
+
+use crate::value::{FunctionSignature, Type, Value};
+use crate::vm::{NativeFunction, VM};
+use ordered_float::OrderedFloat;
+use std::rc::Rc;
+
+type Native = fn(&[Value]) -> Result<Value, String>;
+
+/// Every prelude function, in the order they are loaded.
+fn natives() -> Vec<(FunctionSignature, Native)> {
+    use Type::{Float, Int};
+    let strings = || Type::Array(Box::new(Type::String));
+    let native = |name: &str, params: Vec<Type>, ret: Type, function: Native| {
+        (
+            FunctionSignature {
+                name: name.to_string(),
+                params,
+                ret,
+                native: true,
+            },
+            function,
+        )
+    };
+    vec![
+        native("abs", vec![Int], Int, |args| {
+            int(args, 0)?
+                .checked_abs()
+                .map(Value::Int)
+                .ok_or_else(|| "Integer overflow".to_string())
+        }),
+        native("min", vec![Int, Int], Int, |args| {
+            Ok(Value::Int(int(args, 0)?.min(int(args, 1)?)))
+        }),
+        native("max", vec![Int, Int], Int, |args| {
+            Ok(Value::Int(int(args, 0)?.max(int(args, 1)?)))
+        }),
+        native("fabs", vec![Float], Float, |args| {
+            Ok(float_value(float(args, 0)?.abs()))
+        }),
+        native("fmin", vec![Float, Float], Float, |args| {
+            Ok(float_value(float(args, 0)?.min(float(args, 1)?)))
+        }),
+        native("fmax", vec![Float, Float], Float, |args| {
+            Ok(float_value(float(args, 0)?.max(float(args, 1)?)))
+        }),
+        native("sqrt", vec![Float], Float, |args| {
+            Ok(float_value(float(args, 0)?.sqrt()))
+        }),
+        native("pow", vec![Float, Float], Float, |args| {
+            Ok(float_value(float(args, 0)?.powf(float(args, 1)?)))
+        }),
+        native("floor", vec![Float], Float, |args| {
+            Ok(float_value(float(args, 0)?.floor()))
+        }),
+        native("ceil", vec![Float], Float, |args| {
+            Ok(float_value(float(args, 0)?.ceil()))
+        }),
+        native("round", vec![Float], Float, |args| {
+            Ok(float_value(float(args, 0)?.round()))
+        }),
+        // strings are indexed by byte, like len counts them
+        native(
+            "substring",
+            vec![Type::String, Int, Int],
+            Type::String,
+            |args| {
+                let s = string(args, 0)?;
+                let (from, to) = (int(args, 1)?, int(args, 2)?);
+                // fails on negative or reversed bounds, and inside a character
+                usize::try_from(from)
+                    .ok()
+                    .zip(usize::try_from(to).ok())
+                    .and_then(|(from, to)| s.get(from..to))
+                    .map(|part| string_value(part.to_string()))
+                    .ok_or_else(|| {
+                        format!(
+                            "Cannot take bytes {from} to {to} of a string of length {}",
+                            s.len()
+                        )
+                    })
+            },
+        ),
+        native("byteAt", vec![Type::String, Int], Int, |args| {
+            let s = string(args, 0)?;
+            let i = int(args, 1)?;
+            usize::try_from(i)
+                .ok()
+                .and_then(|i| s.as_bytes().get(i))
+                .map(|byte| Value::Int(*byte as i64))
+                .ok_or_else(|| format!("Index {i} out of bounds for string of length {}", s.len()))
+        }),
+        native("chars", vec![Type::String], strings(), |args| {
+            let chars = string(args, 0)?
+                .chars()
+                .map(|c| string_value(c.to_string()))
+                .collect();
+            Ok(Value::Arr(Rc::new(chars)))
+        }),
+        native(
+            "split",
+            vec![Type::String, Type::String],
+            strings(),
+            |args| {
+                let (s, separator) = (string(args, 0)?, string(args, 1)?);
+                if separator.is_empty() {
+                    return Err("Cannot split on an empty separator".to_string());
+                }
+                let parts = s
+                    .split(separator)
+                    .map(|part| string_value(part.to_string()))
+                    .collect();
+                Ok(Value::Arr(Rc::new(parts)))
+            },
+        ),
+        native(
+            "join",
+            vec![strings(), Type::String],
+            Type::String,
+            |args| {
+                let Some(Value::Arr(parts)) = args.first() else {
+                    return Err("Argument 1 should be an array".to_string());
+                };
+                let separator = string(args, 1)?;
+                let mut joined = String::new();
+                for (i, part) in parts.iter().enumerate() {
+                    if i > 0 {
+                        joined.push_str(separator);
+                    }
+                    match part {
+                        Value::Str(part) => joined.push_str(part),
+                        _ => return Err("Argument 1 should hold strings".to_string()),
+                    }
+                }
+                Ok(string_value(joined))
+            },
+        ),
+        native("trim", vec![Type::String], Type::String, |args| {
+            Ok(string_value(string(args, 0)?.trim().to_string()))
+        }),
+        native("upper", vec![Type::String], Type::String, |args| {
+            Ok(string_value(string(args, 0)?.to_uppercase()))
+        }),
+        native("lower", vec![Type::String], Type::String, |args| {
+            Ok(string_value(string(args, 0)?.to_lowercase()))
+        }),
+        // parsing returns a single value, or an empty array when it fails
+        native(
+            "parseInt",
+            vec![Type::String],
+            Type::Array(Box::new(Int)),
+            |args| {
+                let parsed = string(args, 0)?.trim().parse().ok().map(Value::Int);
+                Ok(Value::Arr(Rc::new(parsed.into_iter().collect())))
+            },
+        ),
+        native(
+            "parseFloat",
+            vec![Type::String],
+            Type::Array(Box::new(Float)),
+            |args| {
+                let parsed = string(args, 0)?.trim().parse().ok().map(float_value);
+                Ok(Value::Arr(Rc::new(parsed.into_iter().collect())))
+            },
+        ),
+    ]
+}
+
+/// Adds the prelude to `vm` and `function_signatures`, so code compiled
+/// afterwards can call it.
+pub fn load(vm: &mut VM, function_signatures: &mut Vec<FunctionSignature>) {
+    for (signature, function) in natives() {
+        function_signatures.push(signature);
+        vm.give_native(Rc::new(function));
+    }
+    vm.update_function_signatures(function_signatures);
+}
+
+/// Signatures of the prelude, for compiling without a VM.
+pub fn signatures() -> Vec<FunctionSignature> {
+    natives()
+        .into_iter()
+        .map(|(signature, _)| signature)
+        .collect()
+}
+
+/// The prelude function with this exact signature, for linking a compiled
+/// program that calls it.
+pub fn find(signature: &FunctionSignature) -> Option<NativeFunction> {
+    natives()
+        .into_iter()
+        .find(|(s, _)| s == signature)
+        .map(|(_, function)| Rc::new(function) as NativeFunction)
+}
+
+// Natives are only called with arguments of their parameter types, so these
+// only fail when the VM is misused.
+fn int(args: &[Value], i: usize) -> Result<i64, String> {
+    match args.get(i) {
+        Some(Value::Int(n)) => Ok(*n),
+        _ => Err(format!("Argument {} should be an int", i + 1)),
+    }
+}
+fn float(args: &[Value], i: usize) -> Result<f64, String> {
+    match args.get(i) {
+        Some(Value::Float(f)) => Ok(f.0),
+        _ => Err(format!("Argument {} should be a float", i + 1)),
+    }
+}
+fn string(args: &[Value], i: usize) -> Result<&str, String> {
+    match args.get(i) {
+        Some(Value::Str(s)) => Ok(s),
+        _ => Err(format!("Argument {} should be a string", i + 1)),
+    }
+}
+fn float_value(f: f64) -> Value {
+    Value::Float(OrderedFloat(f))
+}
+fn string_value(s: String) -> Value {
+    Value::Str(Rc::new(s))
+}
@@ -1,8 +1,9 @@
 use crate::value::Type;
-use phf::{phf_map, Map};
+use phf::{Map, phf_map};
 use regex::Regex;
-use regex_split::RegexSplit;
 use std::io::Read;
+use std::rc::Rc;
+use std::sync::LazyLock;
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum Delimeter {
@@ -14,6 +15,7 @@ pub enum Delimeter {
     Semicolon,
     LBracket,
     RBracket,
+    Arrow,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
@@ -37,6 +39,13 @@ impl std::fmt::Display for Type {
                 x.fmt(f)?;
                 write!(f, "]")
             }
+            Type::Function(params, ret) => {
+                write!(f, "(")?;
+                for param in params {
+                    write!(f, "{param} ")?;
+                }
+                write!(f, "-> {ret})")
+            }
             Type::AnyType => write!(f, "any"),
         }
     }
@@ -62,8 +71,22 @@ pub enum Operator {
     Concat,
     Index,
     Length,
+    ToFloat,
+    ToInt,
+    ToString,
+    Range,
+    Slice,
+    Reverse,
+    Sort,
+    Contains,
+    IndexOf,
+    Print,
+    Println,
+    ReadLine,
+    ReadInt,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PreToken {
     DEL(Delimeter),
@@ -80,6 +103,7 @@ const TOKEN_MAP: Map<&str, PreToken> = phf_map! {
 ")" => PreToken::DEL(Delimeter::RPar),
 "[" => PreToken::DEL(Delimeter::LBracket),
 "]" => PreToken::DEL(Delimeter::RBracket),
+"->" => PreToken::DEL(Delimeter::Arrow),
 "." => PreToken::DEL(Delimeter::Dot),
 ":" => PreToken::DEL(Delimeter::Colon),
 ";" => PreToken::DEL(Delimeter::Semicolon),
@@ -113,6 +137,29 @@ const TOKEN_MAP: Map<&str, PreToken> = phf_map! {
 "#" => PreToken::COMMENT,
 };
 
+/// Operators spelled like names. They are tokenized as symbols, so that a
+/// definition or local of the same name shadows them.
+const BUILTIN_MAP: Map<&str, Operator> = phf_map! {
+"toFloat" => Operator::ToFloat,
+"toInt" => Operator::ToInt,
+"toString" => Operator::ToString,
+"range" => Operator::Range,
+"slice" => Operator::Slice,
+"reverse" => Operator::Reverse,
+"sort" => Operator::Sort,
+"contains" => Operator::Contains,
+"indexOf" => Operator::IndexOf,
+"print" => Operator::Print,
+"println" => Operator::Println,
+"readLine" => Operator::ReadLine,
+"readInt" => Operator::ReadInt,
+};
+
+/// The builtin operator called `name`, if there is one.
+pub fn builtin(name: &str) -> Option<Operator> {
+    BUILTIN_MAP.get(name).copied()
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PreTokenized {
     T(PreToken),
@@ -127,31 +174,83 @@ fn string_to_tokenize(s: &str) -> PreTokenized {
     }
 }
 
-pub fn tokenize_line(line: String) -> Vec<PreTokenized> {
-    let re = Regex::new("(\".*\"|\\(|\\)|\\|\\+|\\-|\\*|/|,|:=|=>|;|\\[|\\])").unwrap();
-    let mut split: Vec<PreTokenized> = re
-        .split_inclusive(line.as_str())
-        .flat_map(|s| re.split_inclusive_left(s))
-        .flat_map(|s| {
-            if re.is_match(s) {
-                vec![s]
-            } else {
-                s.split_whitespace().collect()
-            }
-        })
-        .map(string_to_tokenize)
-        .filter(|t| t != &PreTokenized::T(PreToken::COMMENT))
-        .filter(|t| t != &PreTokenized::T(PreToken::DEL(Delimeter::Semicolon)))
-        .filter(|t| t != &PreTokenized::T(PreToken::DEL(Delimeter::Comma)))
-        .filter(|t| t != &PreTokenized::T(PreToken::DEL(Delimeter::LPar)))
-        .filter(|t| t != &PreTokenized::T(PreToken::DEL(Delimeter::RPar)))
+// compiled once, since building a regex costs far more than matching a line
+static DELIMITERS: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new("(\"[^\"]*\"|\\(|\\)|\\|\\+|->|\\-|\\*|/|,|:=|=>|;|\\[|\\])").unwrap()
+});
+static WORD: LazyLock<Regex> = LazyLock::new(|| Regex::new("\\S+").unwrap());
+
+pub fn tokenize_line(line: &str) -> Vec<(PreTokenized, usize)> {
+    let re = &*DELIMITERS;
+    let word = &*WORD;
+    let mut pieces: Vec<(&str, usize)> = Vec::new();
+    let mut last = 0;
+    for m in re.find_iter(line) {
+        pieces.extend(
+            word.find_iter(&line[last..m.start()])
+                .map(|w| (w.as_str(), last + w.start())),
+        );
+        pieces.push((m.as_str(), m.start()));
+        last = m.end();
+    }
+    pieces.extend(
+        word.find_iter(&line[last..])
+            .map(|w| (w.as_str(), last + w.start())),
+    );
+    let mut split: Vec<(PreTokenized, usize)> = pieces
+        .into_iter()
+        .map(|(s, offset)| (string_to_tokenize(s), column_of(line, offset)))
+        .filter(|(t, _)| t != &PreTokenized::T(PreToken::COMMENT))
+        .filter(|(t, _)| t != &PreTokenized::T(PreToken::DEL(Delimeter::Semicolon)))
+        .filter(|(t, _)| t != &PreTokenized::T(PreToken::DEL(Delimeter::Comma)))
         .collect();
-    split.push(PreTokenized::T(PreToken::EOL));
+    split.push((PreTokenized::T(PreToken::EOL), column_of(line, line.len())));
     split
 }
 
+/// 1-based character column of a byte offset into `line`.
+fn column_of(line: &str, offset: usize) -> usize {
+    line[..offset].chars().count() + 1
+}
+
+/// Location of a token in the source, along with the text of its line so
+/// errors can point at it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+    pub source: Rc<str>,
+}
+
+impl Span {
+    /// Writes the source line followed by a caret under the column.
+    pub fn fmt_source(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "\t{}", self.source)?;
+        let padding: String = self
+            .source
+            .chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        write!(f, "\t{padding}^")
+    }
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
 pub struct Scanner {
-    lines_stack: Vec<String>,
+    lines_stack: Vec<(Rc<str>, usize, String)>,
+}
+
+impl Default for Scanner {
+    fn default() -> Self {
+        Scanner::new()
+    }
 }
 
 impl Scanner {
@@ -165,19 +264,23 @@ impl Scanner {
         let mut file = std::fs::File::open(path)?;
         let mut contents = String::new();
         file.read_to_string(&mut contents)?;
+        let file_name: Rc<str> = path.into();
         let re = Regex::new("(include )(.+)").unwrap();
-        for line in contents.lines().rev() {
+        let lines: Vec<&str> = contents.lines().collect();
+        for (i, line) in lines.into_iter().enumerate().rev() {
             if line.starts_with("include") {
                 let include_path = re.captures(line).unwrap().get(2).unwrap().as_str();
                 self.load_file(include_path)?;
             } else {
-                self.lines_stack.push(line.to_owned());
+                self.lines_stack
+                    .push((file_name.clone(), i + 1, line.to_owned()));
             }
         }
         Ok(())
     }
 
-    pub fn get_next_line(&mut self) -> Option<String> {
+    /// Returns the next line along with the file it came from and its line number.
+    pub fn get_next_line(&mut self) -> Option<(Rc<str>, usize, String)> {
         self.lines_stack.pop()
     }
 }
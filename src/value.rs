@@ -1,11 +1,9 @@
 use crate::parser::Literal;
 use ordered_float::OrderedFloat;
 use std::boxed::Box;
-use std::boxed::ThinBox;
-use std::mem::ManuallyDrop;
-use std::ops::Deref;
-use std::ops::DerefMut;
+use std::rc::Rc;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Debug, Clone)]
 pub enum Type {
     Float,
@@ -13,6 +11,8 @@ pub enum Type {
     Bool,
     String,
     Array(Box<Type>),
+    /// Parameter types and return type of a function value.
+    Function(Vec<Type>, Box<Type>),
     AnyType,
 }
 
@@ -24,6 +24,9 @@ impl PartialEq for Type {
             (Type::Bool, Type::Bool) => true,
             (Type::String, Type::String) => true,
             (Type::Array(x), Type::Array(y)) => *x == *y,
+            (Type::Function(x_params, x_ret), Type::Function(y_params, y_ret)) => {
+                x_params == y_params && x_ret == y_ret
+            }
             (Type::AnyType, Type::Int) => true,
             (Type::AnyType, Type::Float) => true,
             (Type::AnyType, Type::Bool) => true,
@@ -39,130 +42,161 @@ impl PartialEq for Type {
 
 impl Eq for Type {}
 
-impl Type {
-    pub fn array_depth(&self) -> u8 {
-        match self {
-            Type::Array(t) => 1 + t.deref().array_depth(),
-            _ => 0,
+/// The name and type of a function. Its index in the list of signatures is
+/// also the index of its chunk.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FunctionSignature {
+    pub name: String,
+    pub params: Vec<Type>,
+    pub ret: Type,
+    /// Implemented by the host instead of a chunk, and called with `NativeCall`.
+    pub native: bool,
+}
+
+impl FunctionSignature {
+    pub fn new(name: String, params: Vec<Type>, ret: Type) -> FunctionSignature {
+        FunctionSignature {
+            name,
+            params,
+            ret,
+            native: false,
         }
     }
 }
 
-pub union Value {
-    pub f: OrderedFloat<f64>,
-    pub i: i64,
-    pub b: bool,
-    pub a: ManuallyDrop<ThinBox<Vec<Value>>>,
-    pub s: ManuallyDrop<ThinBox<String>>, // TODO: ThinBox<str>
+/// A runtime value. Strings, arrays and closures are reference counted, so
+/// copying a value is cheap and every copy frees itself when dropped.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Float(OrderedFloat<f64>),
+    Int(i64),
+    Bool(bool),
+    Str(Rc<String>),
+    Arr(Rc<Vec<Value>>),
+    Fn(Rc<Closure>),
 }
 
-impl Clone for Value {
-    fn clone(&self) -> Self {
-        unsafe { std::mem::transmute_copy(self) }
-    }
+/// A function value: the function to call and the values it captured, which
+/// are passed as its first arguments.
+#[derive(Debug)]
+pub struct Closure {
+    pub function: usize,
+    pub captures: Vec<Value>,
 }
 
-impl Value {
-    pub fn recursive_drop(&mut self, depth: u8) {
-        if depth != 0 {
-            unsafe {
-                for item in self.a.deref_mut().iter_mut() {
-                    item.clone().recursive_drop(depth - 1);
+impl Drop for Closure {
+    /// A closure can capture another closure, so a long chain would free
+    /// itself recursively. Unwrap the chain onto a worklist instead.
+    fn drop(&mut self) {
+        let mut pending = std::mem::take(&mut self.captures);
+        while let Some(value) = pending.pop() {
+            match value {
+                Value::Fn(closure) => {
+                    if let Ok(mut closure) = Rc::try_unwrap(closure) {
+                        pending.append(&mut closure.captures);
+                    }
                 }
-                ManuallyDrop::drop(&mut self.a);
+                Value::Arr(items) => {
+                    if let Ok(mut items) = Rc::try_unwrap(items) {
+                        pending.append(&mut items);
+                    }
+                }
+                _ => {}
             }
         }
     }
-    pub fn clone_str(&self) -> Self {
-        unsafe {
-            Value {
-                s: ManuallyDrop::new(ThinBox::new(self.s.deref().to_string().clone())),
-            }
-        }
+}
+
+/// An owned copy of a value, as returned from a run of main.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BreakoutValue {
+    Int(i64),
+    Float(f64),
+    Bool(bool),
+    String(String),
+    Array(Vec<BreakoutValue>),
+    Function(FunctionValue),
+}
+
+/// A function value taken out of a run. It keeps the values its lambda
+/// captured, so passing it back in calls the same closure.
+#[derive(Debug, Clone)]
+pub struct FunctionValue(pub(crate) Rc<Closure>);
+
+impl FunctionValue {
+    /// The index of the function it calls.
+    pub fn function(&self) -> usize {
+        self.0.function
     }
-    pub fn clone_arr(&self) -> Self {
-        unsafe {
-            Value {
-                a: ManuallyDrop::new(ThinBox::new(self.a.deref().to_vec())),
-            }
-        }
+}
+
+/// Captured values cannot be compared, so function values are only equal
+/// when they are the same closure or call the same function without
+/// capturing anything.
+impl PartialEq for FunctionValue {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+            || (self.0.function == other.0.function
+                && self.0.captures.is_empty()
+                && other.0.captures.is_empty())
     }
 }
 
-impl Value {
-    pub fn fmt(&self, t: &Type, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match t {
-            Type::Float => self.fmt_float(self, f),
-            Type::Int => self.fmt_int(self, f),
-            Type::Bool => self.fmt_bool(self, f),
-            Type::String => self.fmt_string(self, f),
-            Type::Array(sub_type) => self.fmt_array(self, &sub_type.deref().clone(), f),
-            Type::AnyType => panic!("AnyType"),
+impl From<&Value> for BreakoutValue {
+    fn from(value: &Value) -> Self {
+        match value {
+            Value::Float(f) => BreakoutValue::Float(f.into_inner()),
+            Value::Int(i) => BreakoutValue::Int(*i),
+            Value::Bool(b) => BreakoutValue::Bool(*b),
+            Value::Str(s) => BreakoutValue::String(s.to_string()),
+            Value::Arr(items) => BreakoutValue::Array(items.iter().map(Self::from).collect()),
+            Value::Fn(closure) => BreakoutValue::Function(FunctionValue(closure.clone())),
         }
     }
-    fn fmt_float(&self, v: &Value, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", unsafe { v.f })
-    }
-    fn fmt_int(&self, v: &Value, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", unsafe { v.i })
-    }
-    fn fmt_bool(&self, v: &Value, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", unsafe { v.b })
-    }
-    fn fmt_string(&self, v: &Value, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "\"")?;
-        write!(f, "{}", unsafe { v.s.deref() })?;
-        write!(f, "\"")
-    }
-    fn fmt_array(
-        &self,
-        v: &Value,
-        sub_type: &Type,
-        f: &mut std::fmt::Formatter<'_>,
-    ) -> std::fmt::Result {
-        unsafe {
-            write!(f, "[")?;
-            let len = v.a.len();
-            for (i, item) in v.a.clone().into_iter().enumerate() {
-                match sub_type {
-                    Type::Float => self.fmt_float(&item, f)?,
-                    Type::Int => self.fmt_int(&item, f)?,
-                    Type::Bool => self.fmt_bool(&item, f)?,
-                    Type::String => self.fmt_string(&item, f)?,
-                    Type::Array(sub_sub_type) => {
-                        self.fmt_array(&item, &sub_sub_type.deref().clone(), f)?
-                    }
-                    Type::AnyType => panic!("AnyType"),
-                };
-                if i != len - 1 {
-                    write!(f, " ")?;
-                }
+}
+
+impl From<&BreakoutValue> for Value {
+    fn from(value: &BreakoutValue) -> Self {
+        match value {
+            BreakoutValue::Int(i) => Value::Int(*i),
+            BreakoutValue::Float(f) => Value::Float(OrderedFloat(*f)),
+            BreakoutValue::Bool(b) => Value::Bool(*b),
+            BreakoutValue::String(s) => Value::Str(Rc::new(s.clone())),
+            BreakoutValue::Array(items) => {
+                Value::Arr(Rc::new(items.iter().map(Self::from).collect()))
             }
-            write!(f, "]")?;
+            BreakoutValue::Function(function) => Value::Fn(function.0.clone()),
         }
-        Ok(())
     }
 }
 
-pub struct PrintValWrapper<'v> {
-    pub val: &'v Value,
-    pub t: &'v Type,
-}
-
-impl std::fmt::Display for PrintValWrapper<'_> {
+impl std::fmt::Display for BreakoutValue {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        self.val.fmt(self.t, f)?;
-        writeln!(f)
+        match self {
+            BreakoutValue::Int(n) => write!(f, "{n}"),
+            BreakoutValue::Float(n) => write!(f, "{n}"),
+            BreakoutValue::Bool(b) => write!(f, "{b}"),
+            BreakoutValue::String(s) => write!(f, "\"{s}\""),
+            BreakoutValue::Array(items) => {
+                write!(f, "[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, "]")
+            }
+            BreakoutValue::Function(function) => write!(f, "<function {}>", function.function()),
+        }
     }
 }
 
 pub fn val_from_literal(lit: Literal) -> Value {
     match lit {
-        Literal::Float(f) => Value { f },
-        Literal::Integer(i) => Value { i },
-        Literal::Bool(b) => Value { b },
-        Literal::String(s) => Value {
-            s: ManuallyDrop::new(ThinBox::new(s)),
-        },
+        Literal::Float(f) => Value::Float(f),
+        Literal::Integer(i) => Value::Int(i),
+        Literal::Bool(b) => Value::Bool(b),
+        Literal::String(s) => Value::Str(Rc::new(s)),
     }
 }
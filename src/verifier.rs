@@ -0,0 +1,375 @@
+use crate::chunk::Chunk;
+use crate::common::{OpCode, StackEffect};
+use crate::value::FunctionSignature;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Clone)]
+pub struct VerifyError {
+    pub function: usize,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Invalid bytecode in function {} at offset {}:\n\t{}",
+            self.function, self.offset, self.message
+        )
+    }
+}
+impl Error for VerifyError {}
+
+/// Checks that every chunk only holds real opcodes with in-range operands,
+/// that jumps land on instruction boundaries, and that the value stack depth
+/// and number of bound locals are the same whichever path reaches an
+/// instruction. The VM trusts all of this when it reads operands out of
+/// `ChunkData`. When there is a `main`, it must be a function of this
+/// program that takes no arguments.
+pub fn verify(
+    chunks: &[Chunk],
+    constants_len: usize,
+    function_signatures: &[FunctionSignature],
+    main: Option<usize>,
+) -> Result<(), VerifyError> {
+    if chunks.len() > function_signatures.len() {
+        return Err(VerifyError {
+            function: function_signatures.len(),
+            offset: 0,
+            message: "Function has no signature".to_string(),
+        });
+    }
+    if let Some(main) = main {
+        let error = |message: String| VerifyError {
+            function: main,
+            offset: 0,
+            message,
+        };
+        match function_signatures.get(main) {
+            _ if main >= chunks.len() => {
+                return Err(error(format!("Main function {main} out of range")));
+            }
+            Some(signature) if signature.native => {
+                return Err(error("Main cannot be a native function".to_string()));
+            }
+            Some(signature) if !signature.params.is_empty() => {
+                return Err(error(format!(
+                    "Main takes {} arguments instead of none",
+                    signature.params.len()
+                )));
+            }
+            _ => {}
+        }
+    }
+    for (function, chunk) in chunks.iter().enumerate() {
+        if function_signatures[function].native {
+            continue;
+        }
+        verify_chunk(
+            function,
+            chunk,
+            chunks.len(),
+            constants_len,
+            function_signatures,
+        )?;
+    }
+    Ok(())
+}
+
+fn verify_chunk(
+    function: usize,
+    chunk: &Chunk,
+    chunks_len: usize,
+    constants_len: usize,
+    function_signatures: &[FunctionSignature],
+) -> Result<(), VerifyError> {
+    let error = |offset: usize, message: String| VerifyError {
+        function,
+        offset,
+        message,
+    };
+    let bytes = chunk.as_bytes();
+    let params = function_signatures[function].params.len();
+
+    // decode every instruction and check its operands
+    let mut boundaries = vec![false; bytes.len()];
+    let mut offset = 0;
+    while offset < bytes.len() {
+        if OpCode::from(bytes[offset]) == OpCode::NullCode {
+            return Err(error(offset, format!("Invalid opcode {}", bytes[offset])));
+        }
+        let (oc, operands, len) = chunk
+            .decode(offset)
+            .ok_or_else(|| error(offset, "Truncated instruction".to_string()))?;
+        boundaries[offset] = true;
+        match oc {
+            OpCode::Constant | OpCode::ConstantLong if operands[0] >= constants_len => {
+                return Err(error(
+                    offset,
+                    format!("Constant {} out of range", operands[0]),
+                ));
+            }
+            OpCode::PushFunction | OpCode::PushFunctionLong if operands[0] >= chunks_len => {
+                return Err(error(
+                    offset,
+                    format!("Function {} out of range", operands[0]),
+                ));
+            }
+            OpCode::MakeClosure | OpCode::MakeClosureLong => {
+                let callee = operands[0];
+                if callee >= chunks_len {
+                    return Err(error(offset, format!("Function {callee} out of range")));
+                }
+                let arity = function_signatures[callee].params.len();
+                if operands[1] > arity {
+                    return Err(error(
+                        offset,
+                        format!(
+                            "Function {callee} takes {arity} arguments, captures {}",
+                            operands[1]
+                        ),
+                    ));
+                }
+            }
+            OpCode::FunctionCall
+            | OpCode::FunctionCallLong
+            | OpCode::TailCall
+            | OpCode::TailCallLong
+            | OpCode::NativeCall
+            | OpCode::NativeCallLong => {
+                let callee = operands[0];
+                if callee >= chunks_len {
+                    return Err(error(offset, format!("Function {callee} out of range")));
+                }
+                let native = matches!(oc, OpCode::NativeCall | OpCode::NativeCallLong);
+                if function_signatures[callee].native != native {
+                    return Err(error(
+                        offset,
+                        format!("{} cannot call function {callee}", oc.info().mnemonic),
+                    ));
+                }
+                let arity = function_signatures[callee].params.len();
+                if operands[1] != arity {
+                    return Err(error(
+                        offset,
+                        format!(
+                            "Function {callee} takes {arity} arguments, called with {}",
+                            operands[1]
+                        ),
+                    ));
+                }
+            }
+            _ => {}
+        }
+        offset += len;
+    }
+
+    // walk every path, tracking the value stack depth and how many local
+    // slots are bound
+    let mut states: Vec<Option<(usize, usize)>> = vec![None; bytes.len()];
+    let mut pending = vec![(0, 0, params)];
+    while let Some((offset, depth, locals)) = pending.pop() {
+        if offset >= bytes.len() {
+            return Err(error(
+                offset,
+                "Execution runs off the end of the chunk".to_string(),
+            ));
+        }
+        if !boundaries[offset] {
+            return Err(error(
+                offset,
+                "Jump target is not an instruction boundary".to_string(),
+            ));
+        }
+        match states[offset] {
+            Some(seen) if seen == (depth, locals) => continue,
+            Some((seen, _)) if seen != depth => {
+                return Err(error(
+                    offset,
+                    format!("Stack depth {depth} here, but {seen} on another path"),
+                ));
+            }
+            Some((_, seen)) => {
+                return Err(error(
+                    offset,
+                    format!("{locals} locals bound here, but {seen} on another path"),
+                ));
+            }
+            None => states[offset] = Some((depth, locals)),
+        }
+        let (oc, operands, len) = chunk.decode(offset).unwrap();
+        let next = offset + len;
+        let (pops, pushes) = match oc.info().stack_effect {
+            StackEffect::Fixed(pops, pushes) => (pops, pushes),
+            StackEffect::PopsOperand(i, extra) => (operands[i] + extra, 1),
+        };
+        if pops > depth {
+            return Err(error(offset, "Stack underflow".to_string()));
+        }
+        let depth = depth - pops + pushes;
+        let locals = match oc {
+            OpCode::StackLoadLocalVar | OpCode::StackLoadLocalVarLong if operands[0] >= locals => {
+                return Err(error(offset, format!("Local {} out of range", operands[0])));
+            }
+            OpCode::BindLocal => locals + 1,
+            OpCode::PopLocal if locals <= params => {
+                return Err(error(offset, "No bound local to pop".to_string()));
+            }
+            OpCode::PopLocal => locals - 1,
+            _ => locals,
+        };
+        match oc {
+            OpCode::Return => {
+                if depth != 0 {
+                    return Err(error(
+                        offset,
+                        format!("Returns with {} values on the stack", depth + 1),
+                    ));
+                }
+            }
+            OpCode::TailCall
+            | OpCode::TailCallLong
+            | OpCode::TailCallIndirect
+            | OpCode::TailCallIndirectLong => {
+                // the callee's result is the only value left
+                if depth != 1 {
+                    return Err(error(
+                        offset,
+                        format!("Tail call leaves {} values on the stack", depth - 1),
+                    ));
+                }
+            }
+            OpCode::Advance | OpCode::AdvanceLong => {
+                pending.push((next + operands[0], depth, locals))
+            }
+            OpCode::AdvanceIfFalse | OpCode::AdvanceIfFalseLong => {
+                pending.push((next + operands[0], depth, locals));
+                pending.push((next, depth, locals));
+            }
+            _ => pending.push((next, depth, locals)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::value::Type;
+
+    fn function(params: Vec<Type>) -> FunctionSignature {
+        FunctionSignature::new("f".to_string(), params, Type::Int)
+    }
+
+    /// Verifies a program of one function without parameters, with a single
+    /// constant, returning the error message if it is rejected.
+    fn check(bytes: &[u8]) -> Result<(), String> {
+        let chunk = Chunk::from_parts(bytes, Vec::new());
+        verify(&[chunk], 1, &[function(Vec::new())], Some(0)).map_err(|e| e.message)
+    }
+
+    fn op(opcode: OpCode) -> u8 {
+        opcode as u8
+    }
+
+    #[test]
+    fn accepts_valid_code() {
+        assert_eq!(
+            check(&[op(OpCode::Constant), 0, op(OpCode::Return)]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_opcodes_and_operands() {
+        assert_eq!(check(&[255]), Err("Invalid opcode 255".to_string()));
+        assert_eq!(
+            check(&[op(OpCode::Constant)]),
+            Err("Truncated instruction".to_string())
+        );
+        assert_eq!(
+            check(&[op(OpCode::Constant), 1, op(OpCode::Return)]),
+            Err("Constant 1 out of range".to_string())
+        );
+        assert_eq!(
+            check(&[op(OpCode::PushFunction), 3, op(OpCode::Return)]),
+            Err("Function 3 out of range".to_string())
+        );
+        assert_eq!(
+            check(&[op(OpCode::FunctionCall), 0, 2, op(OpCode::Return)]),
+            Err("Function 0 takes 0 arguments, called with 2".to_string())
+        );
+        assert_eq!(
+            check(&[op(OpCode::NativeCall), 0, 0, op(OpCode::Return)]),
+            Err("NativeCall cannot call function 0".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_control_flow() {
+        // jumps one byte into the Constant instruction
+        assert_eq!(
+            check(&[
+                op(OpCode::Advance),
+                1,
+                op(OpCode::Constant),
+                0,
+                op(OpCode::Return)
+            ]),
+            Err("Jump target is not an instruction boundary".to_string())
+        );
+        assert_eq!(
+            check(&[op(OpCode::Constant), 0]),
+            Err("Execution runs off the end of the chunk".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_unbalanced_stacks() {
+        assert_eq!(
+            check(&[op(OpCode::AddI), op(OpCode::Return)]),
+            Err("Stack underflow".to_string())
+        );
+        assert_eq!(
+            check(&[
+                op(OpCode::Constant),
+                0,
+                op(OpCode::Constant),
+                0,
+                op(OpCode::Return)
+            ]),
+            Err("Returns with 2 values on the stack".to_string())
+        );
+        // one path pushes a constant before joining the other
+        assert_eq!(
+            check(&[
+                op(OpCode::True),
+                op(OpCode::AdvanceIfFalse),
+                2,
+                op(OpCode::Constant),
+                0,
+                op(OpCode::Return)
+            ]),
+            Err("Stack depth 0 here, but 1 on another path".to_string())
+        );
+        assert_eq!(
+            check(&[op(OpCode::PopLocal), op(OpCode::True), op(OpCode::Return)]),
+            Err("No bound local to pop".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_bad_main() {
+        let chunk = || Chunk::from_parts(&[op(OpCode::True), op(OpCode::Return)], Vec::new());
+        let error = verify(&[chunk()], 0, &[function(Vec::new())], Some(200)).unwrap_err();
+        assert_eq!(error.message, "Main function 200 out of range");
+        let error = verify(&[chunk()], 0, &[function(vec![Type::Int])], Some(0)).unwrap_err();
+        assert_eq!(error.message, "Main takes 1 arguments instead of none");
+        let mut native = function(Vec::new());
+        native.native = true;
+        let error = verify(&[Chunk::new(Vec::new())], 0, &[native], Some(0)).unwrap_err();
+        assert_eq!(error.message, "Main cannot be a native function");
+    }
+}
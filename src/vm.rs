@@ -1,394 +1,973 @@
 use crate::chunk::Chunk;
 use crate::common::OpCode;
-use crate::value::{PrintValWrapper, Type, Value};
-use std::boxed::ThinBox;
-use std::mem::ManuallyDrop;
-use std::ops::DerefMut;
+use crate::parser::Literal;
+use crate::tokenizer::Span;
+use crate::value::{BreakoutValue, Closure, FunctionSignature, Type, Value, val_from_literal};
+use crate::verifier::{VerifyError, verify};
+use ordered_float::OrderedFloat;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    DivisionByZero,
+    IntegerOverflow,
+    IndexOutOfBounds {
+        index: i64,
+        len: usize,
+    },
+    StackUnderflow,
+    InvalidOpcode(u8),
+    UnexpectedEndOfChunk,
+    InvalidCall {
+        function: usize,
+        args: usize,
+    },
+    TypeMismatch,
+    StackOverflow {
+        calls: usize,
+        values: usize,
+    },
+    OutOfFuel {
+        budget: u64,
+    },
+    /// An instruction would build an array or string longer than allowed.
+    TooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// Reading input or writing output failed.
+    Io(io::ErrorKind),
+    /// A native function returned an error.
+    NativeFailed {
+        name: String,
+        message: String,
+    },
+    /// Main was run, but none was set.
+    NoMain,
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuntimeErrorKind::DivisionByZero => write!(f, "Division by zero"),
+            RuntimeErrorKind::IntegerOverflow => write!(f, "Integer overflow"),
+            RuntimeErrorKind::IndexOutOfBounds { index, len } => {
+                write!(f, "Index {index} out of bounds for array of length {len}")
+            }
+            RuntimeErrorKind::StackUnderflow => write!(f, "Stack underflow"),
+            RuntimeErrorKind::InvalidOpcode(byte) => write!(f, "Invalid opcode {byte}"),
+            RuntimeErrorKind::UnexpectedEndOfChunk => write!(f, "Unexpected end of chunk"),
+            RuntimeErrorKind::InvalidCall { function, args } => {
+                write!(f, "Cannot call function {function} with {args} arguments")
+            }
+            RuntimeErrorKind::TypeMismatch => write!(f, "Value has the wrong type"),
+            RuntimeErrorKind::StackOverflow { calls, values } => write!(
+                f,
+                "Stack overflow with {calls} active calls and {values} values on the stack"
+            ),
+            RuntimeErrorKind::OutOfFuel { budget } => {
+                write!(f, "Ran out of fuel after using {budget}")
+            }
+            RuntimeErrorKind::TooLarge { len, max } => {
+                write!(
+                    f,
+                    "Cannot build a value of length {len}, the limit is {max}"
+                )
+            }
+            RuntimeErrorKind::Io(kind) => write!(f, "Input or output failed: {kind}"),
+            RuntimeErrorKind::NativeFailed { name, message } => write!(f, "{name}: {message}"),
+            RuntimeErrorKind::NoMain => write!(f, "No main function"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StackFrame {
+    pub function: usize,
+    pub name: Option<String>,
+    pub offset: usize,
+    pub span: Option<Span>,
+}
+
+impl fmt::Display for StackFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "at {name}")?,
+            None => write!(f, "at function {}", self.function)?,
+        }
+        match &self.span {
+            Some(span) => write!(f, " ({span})"),
+            None => write!(f, " (offset {})", self.offset),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    /// Active calls at the time of the error, innermost first. The first
+    /// frame holds the function and offset of the failing instruction.
+    pub trace: Vec<StackFrame>,
+}
+
+/// Frames printed from each end of a long trace. The frames in between are
+/// counted instead, since runaway recursion leaves thousands of them.
+const TRACE_INNERMOST: usize = 16;
+const TRACE_OUTERMOST: usize = 4;
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Runtime error:\n\t{}", self.kind)?;
+        let len = self.trace.len();
+        if len <= TRACE_INNERMOST + TRACE_OUTERMOST {
+            for frame in &self.trace {
+                write!(f, "\n\t{frame}")?;
+            }
+            return Ok(());
+        }
+        for frame in &self.trace[..TRACE_INNERMOST] {
+            write!(f, "\n\t{frame}")?;
+        }
+        let elided = len - TRACE_INNERMOST - TRACE_OUTERMOST;
+        write!(f, "\n\t... {elided} more frames")?;
+        for frame in &self.trace[len - TRACE_OUTERMOST..] {
+            write!(f, "\n\t{frame}")?;
+        }
+        Ok(())
+    }
+}
+impl Error for RuntimeError {}
+
+/// Calls that may be active at once before `run` fails with a stack overflow.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 100_000;
+/// Values the value stack may hold when a function is called.
+pub const DEFAULT_MAX_STACK_SIZE: usize = 1_000_000;
+/// Elements an array, or bytes a string, may hold when an instruction builds
+/// it.
+pub const DEFAULT_MAX_ARRAY_LEN: usize = 10_000_000;
+
+/// A function implemented by the host. It is called with arguments matching
+/// its signature and must return a value of its return type, or an error
+/// message that fails the run.
+pub type NativeFunction = Rc<dyn Fn(&[Value]) -> Result<Value, String>>;
 
 pub struct VM {
     program_data: Vec<Chunk>,
+    /// The host function behind each native, at the same index as its
+    /// empty chunk.
+    natives: Vec<Option<NativeFunction>>,
     value_stack: Vec<Value>,
     constants: Vec<Value>,
     function_stack: Vec<(usize, Vec<Value>)>,
-    array_depth_drop_stack: Vec<u8>,
     main_pointer: Option<usize>,
     position_stack: Vec<usize>,
-    main_type: Option<Type>,
+    function_signatures: Vec<FunctionSignature>,
+    max_call_depth: usize,
+    max_stack_size: usize,
+    max_array_len: usize,
+    instruction_count: u64,
+    fuel_used: u64,
+    /// Fuel of the current run, which `fuel_used` may not pass.
+    budget: u64,
+    output: Box<dyn Write>,
+    /// Where reads come from, or stdin when unset. Stdin is not held here, so
+    /// the host can read it between runs.
+    input: Option<Box<dyn BufRead>>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        VM::new()
+    }
 }
 
 impl VM {
     pub fn new() -> VM {
         VM {
             program_data: Vec::new(),
+            natives: Vec::new(),
             value_stack: Vec::new(),
             constants: Vec::new(),
             function_stack: Vec::new(),
-            array_depth_drop_stack: Vec::new(),
             main_pointer: None,
             position_stack: Vec::new(),
-            main_type: None,
+            function_signatures: Vec::new(),
+            max_call_depth: DEFAULT_MAX_CALL_DEPTH,
+            max_stack_size: DEFAULT_MAX_STACK_SIZE,
+            max_array_len: DEFAULT_MAX_ARRAY_LEN,
+            instruction_count: 0,
+            fuel_used: 0,
+            budget: u64::MAX,
+            output: Box::new(io::stdout()),
+            input: None,
         }
     }
+    /// Sets where `print` and `println` write, stdout by default.
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = output;
+    }
+    /// Sets where `readLine` and `readInt` read lines from, stdin by default.
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = Some(input);
+    }
     pub fn give_data(&mut self, data: Chunk) {
         self.program_data.push(data);
+        self.natives.push(None);
+    }
+    /// Adds a native function, which takes the next function index like a
+    /// chunk would. Its signature must be marked `native`.
+    pub fn give_native(&mut self, native: NativeFunction) {
+        self.program_data.push(Chunk::new(Vec::new()));
+        self.natives.push(Some(native));
     }
-    pub fn update_constants(&mut self, constants: &Vec<Value>) {
+    pub fn update_constants(&mut self, constants: &[Literal]) {
         self.constants.clear();
         for c in constants {
-            self.constants.push(c.clone());
+            self.constants.push(val_from_literal(c.clone()));
+        }
+    }
+    /// Forgets every function from index `len` on, along with its signature.
+    pub fn truncate_functions(&mut self, len: usize) {
+        self.program_data.truncate(len);
+        self.natives.truncate(len);
+        self.function_signatures.truncate(len);
+    }
+    pub fn update_function_signatures(&mut self, function_signatures: &[FunctionSignature]) {
+        self.function_signatures = function_signatures.to_vec();
+    }
+    /// Checks every chunk and main with the bytecode verifier. `run` assumes
+    /// this has passed, so set main first.
+    pub fn verify(&self) -> Result<(), VerifyError> {
+        for (function, (signature, native)) in self
+            .function_signatures
+            .iter()
+            .zip(&self.natives)
+            .enumerate()
+        {
+            let message = match (signature.native, native) {
+                (true, None) => format!("No native function registered for {}", signature.name),
+                (false, Some(_)) => format!(
+                    "A native function is registered for {}, which is not native",
+                    signature.name
+                ),
+                _ => continue,
+            };
+            return Err(VerifyError {
+                function,
+                offset: 0,
+                message,
+            });
         }
+        verify(
+            &self.program_data,
+            self.constants.len(),
+            &self.function_signatures,
+            self.main_pointer,
+        )
     }
-    pub fn set_main(&mut self, offset: usize, main_type: Type) {
-        self.main_pointer = match self.main_pointer {
-            None => Some(offset),
-            Some(x) => Some(x + offset + 1),
+    pub fn set_main(&mut self, function: usize) {
+        self.main_pointer = Some(function);
+    }
+    /// Sets how many calls, including main, may be active at once. Tail
+    /// calls reuse their caller's frame and do not count.
+    pub fn set_max_call_depth(&mut self, max_call_depth: usize) {
+        self.max_call_depth = max_call_depth;
+    }
+    /// Sets how many values the value stack may hold when a function is
+    /// called. Within a call the stack only grows by a bounded amount, so
+    /// checking at calls is enough to stop runaway recursion.
+    pub fn set_max_stack_size(&mut self, max_stack_size: usize) {
+        self.max_stack_size = max_stack_size;
+    }
+    /// Sets how long an array, or a string in bytes, may be when an
+    /// instruction such as `range` or `++` builds it.
+    pub fn set_max_array_len(&mut self, max_array_len: usize) {
+        self.max_array_len = max_array_len;
+    }
+    /// Instructions dispatched by the last run, including one that failed.
+    pub fn instruction_count(&self) -> u64 {
+        self.instruction_count
+    }
+    /// Fuel used by the last run, including an instruction that failed.
+    /// Every instruction costs one, and those that build or walk an array or
+    /// string also cost one per element.
+    pub fn fuel_used(&self) -> u64 {
+        self.fuel_used
+    }
+    /// Runs main and returns its result.
+    pub fn run(&mut self) -> Result<BreakoutValue, RuntimeError> {
+        self.run_with_budget(u64::MAX)
+    }
+    /// Runs main, failing with `OutOfFuel` instead of using more than
+    /// `budget` fuel.
+    pub fn run_with_budget(&mut self, budget: u64) -> Result<BreakoutValue, RuntimeError> {
+        let Some(main) = self.main_pointer else {
+            return Err(RuntimeError {
+                kind: RuntimeErrorKind::NoMain,
+                trace: Vec::new(),
+            });
         };
-        self.main_type = Some(main_type);
+        self.call_function(main, Vec::new(), budget)
     }
-    pub fn run(&mut self) -> Result<(), String> {
+    /// Calls `function` with `args`, which must match its signature, and
+    /// returns its result. Fails with `OutOfFuel` instead of using more than
+    /// `budget` fuel.
+    pub fn call_function(
+        &mut self,
+        function: usize,
+        args: Vec<Value>,
+        budget: u64,
+    ) -> Result<BreakoutValue, RuntimeError> {
+        let params = self
+            .function_signatures
+            .get(function)
+            .map(|f| f.params.len());
+        if function >= self.program_data.len() || params != Some(args.len()) {
+            return Err(RuntimeError {
+                kind: RuntimeErrorKind::InvalidCall {
+                    function,
+                    args: args.len(),
+                },
+                trace: Vec::new(),
+            });
+        }
+        self.instruction_count = 0;
+        self.fuel_used = 0;
+        self.budget = budget;
         self.value_stack.clear();
         self.function_stack.clear();
-        self.function_stack
-            .push((self.main_pointer.unwrap(), Vec::new()));
+        self.position_stack.clear();
+        if self.natives[function].is_some() {
+            return self
+                .call_native(function, &args)
+                .map(|value| BreakoutValue::from(&value))
+                .map_err(|kind| RuntimeError {
+                    kind,
+                    trace: Vec::new(),
+                });
+        }
+        self.function_stack.push((function, args));
         for item in self.program_data.iter_mut() {
             item.set_pointer(0);
         }
         loop {
-            let (op, data) =
-                self.program_data[self.function_stack.last().unwrap().0].get_instruction();
-            match op {
-                OpCode::Return => {
-                    if self.function_stack.last().unwrap().0 == self.main_pointer.unwrap() {
-                        self.function_stack.pop();
-                        let wrapper = PrintValWrapper {
-                            val: self.value_stack.last().unwrap(),
-                            t: self.main_type.as_ref().unwrap(),
-                        };
-                        println!("{wrapper}");
-                        let t = self.main_type.as_ref().unwrap();
-                        match t {
-                            Type::Array(_) => {
-                                self.value_stack
-                                    .last_mut()
-                                    .unwrap()
-                                    .recursive_drop(t.array_depth());
-                            }
-                            Type::String => unsafe {
-                                ManuallyDrop::drop(&mut self.value_stack.last_mut().unwrap().s);
-                            },
-                            _ => {}
-                        }
-                        return Ok(());
-                    }
-                    self.function_stack.pop();
-                    self.program_data[self.function_stack.last().unwrap().0]
-                        .set_pointer(self.position_stack.pop().unwrap());
-                }
-                OpCode::Constant => {
-                    let constant = &self.constants[data[0] as usize];
-                    self.value_stack_push(&[constant.clone()]);
+            let offset = self.program_data[self.function_stack.last().unwrap().0].get_pointer();
+            let result = if self.fuel_used < budget {
+                self.instruction_count += 1;
+                self.fuel_used += 1;
+                self.step()
+            } else {
+                Err(RuntimeErrorKind::OutOfFuel { budget })
+            };
+            match result {
+                Ok(None) => {}
+                Ok(Some(value)) => return Ok(BreakoutValue::from(&value)),
+                Err(kind) => {
+                    return Err(RuntimeError {
+                        kind,
+                        trace: self.stack_trace(offset),
+                    });
                 }
-                OpCode::AddI => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.i += b.i;
-                    }
-                }
-                OpCode::AddF => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.f += b.f;
-                    }
-                }
-                OpCode::SubtractI => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.i -= b.i;
-                    }
-                }
-                OpCode::SubtractF => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.f -= b.f;
-                    }
-                }
-                OpCode::MultiplyI => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.i *= b.i;
-                    }
-                }
-                OpCode::MultiplyF => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.f *= b.f;
-                    }
-                }
-                OpCode::DivideI => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.i /= b.i;
-                    }
-                }
-                OpCode::DivideF => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.f /= b.f;
-                    }
-                }
-                OpCode::Mod => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.i %= b.i;
-                    }
-                }
-                OpCode::True => self.value_stack_push(&[Value { b: true }]),
-                OpCode::False => self.value_stack_push(&[Value { b: false }]),
-                OpCode::FunctionCall => {
-                    let next_func = data[0] as usize;
-                    let mut args = Vec::new();
-                    for _ in 0..data[1] {
-                        args.push(self.value_stack_pop());
-                    }
-                    args.reverse();
-                    self.position_stack.push(
-                        self.program_data[self.function_stack.last().unwrap().0].get_pointer(),
-                    );
-                    self.function_stack.push((next_func, args));
-                    self.program_data[self.function_stack.last().unwrap().0].set_pointer(0);
-                }
-                OpCode::StackLoadLocalVar => {
-                    self.value_stack
-                        .push(self.function_stack.last().unwrap().1[data[0] as usize].clone());
-                }
-                OpCode::StackLoadLocalVarArr => {
-                    self.value_stack
-                        .push(self.function_stack.last().unwrap().1[data[0] as usize].clone_arr());
-                    self.array_depth_drop_stack.push(data[1]);
-                }
-                OpCode::StackLoadLocalVarStr => {
-                    self.value_stack
-                        .push(self.function_stack.last().unwrap().1[data[0] as usize].clone_str());
-                }
-                OpCode::DropLocalArr => {
-                    self.function_stack.last_mut().unwrap().1[data[0] as usize]
-                        .recursive_drop(data[1]);
-                }
-                OpCode::DropLocalStr => unsafe {
-                    ManuallyDrop::drop(
-                        &mut self.function_stack.last_mut().unwrap().1[data[0] as usize].s,
-                    );
-                },
-                OpCode::Not => {
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = !a.b;
-                    }
-                }
-                OpCode::EqualI => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.i == b.i;
-                    }
-                }
-                OpCode::EqualF => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.f == b.f;
-                    }
-                }
-                OpCode::EqualB => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.b == b.b;
-                    }
-                }
-                OpCode::EqualS => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.s.as_str() == b.s.as_str();
-                    }
-                }
-                OpCode::LessThanI => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.i < b.i;
-                    }
-                }
-                OpCode::LessThanF => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.f < b.f;
-                    }
-                }
-                OpCode::GreaterThanI => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.i > b.i;
-                    }
-                }
-                OpCode::GreaterThanF => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.f > b.f;
-                    }
-                }
-                OpCode::LessThanOrEqualI => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.i <= b.i;
-                    }
-                }
-                OpCode::LessThanOrEqualF => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.f <= b.f;
-                    }
-                }
-                OpCode::GreaterThanOrEqualI => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.i >= b.i;
-                    }
-                }
-                OpCode::GreaterThanOrEqualF => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.f >= b.f;
-                    }
+            }
+        }
+    }
+    /// Walks the call stack from the innermost frame, which is stopped at `offset`.
+    fn stack_trace(&self, offset: usize) -> Vec<StackFrame> {
+        let mut trace = Vec::new();
+        let mut offset = offset;
+        for (depth, (function, _)) in self.function_stack.iter().enumerate().rev() {
+            trace.push(StackFrame {
+                function: *function,
+                name: self
+                    .function_signatures
+                    .get(*function)
+                    .map(|f| f.name.clone()),
+                offset,
+                span: self.program_data[*function].get_position(offset).cloned(),
+            });
+            if depth > 0 {
+                // return addresses point just past the call instruction
+                offset = self.position_stack[depth - 1] - 1;
+            }
+        }
+        trace
+    }
+    /// Executes a single instruction, returning the result of main once it
+    /// has returned.
+    fn step(&mut self) -> Result<Option<Value>, RuntimeErrorKind> {
+        let (op, data) = self.program_data[self.function_stack.last().unwrap().0]
+            .get_instruction()
+            .ok_or(RuntimeErrorKind::UnexpectedEndOfChunk)?;
+        match op {
+            OpCode::Return => return self.return_from_call(),
+            OpCode::Constant | OpCode::ConstantLong => {
+                let constant = self.constants[data[0]].clone();
+                self.value_stack.push(constant);
+            }
+            OpCode::AddI => self.binary(as_int, |a, b| {
+                a.checked_add(b)
+                    .map(Value::Int)
+                    .ok_or(RuntimeErrorKind::IntegerOverflow)
+            })?,
+            OpCode::AddF => self.binary(as_float, |a, b| Ok(Value::Float(a + b)))?,
+            OpCode::SubtractI => self.binary(as_int, |a, b| {
+                a.checked_sub(b)
+                    .map(Value::Int)
+                    .ok_or(RuntimeErrorKind::IntegerOverflow)
+            })?,
+            OpCode::SubtractF => self.binary(as_float, |a, b| Ok(Value::Float(a - b)))?,
+            OpCode::MultiplyI => self.binary(as_int, |a, b| {
+                a.checked_mul(b)
+                    .map(Value::Int)
+                    .ok_or(RuntimeErrorKind::IntegerOverflow)
+            })?,
+            OpCode::MultiplyF => self.binary(as_float, |a, b| Ok(Value::Float(a * b)))?,
+            OpCode::DivideI => self.binary(as_int, |a, b| {
+                if b == 0 {
+                    return Err(RuntimeErrorKind::DivisionByZero);
+                }
+                a.checked_div(b)
+                    .map(Value::Int)
+                    .ok_or(RuntimeErrorKind::IntegerOverflow)
+            })?,
+            OpCode::DivideF => self.binary(as_float, |a, b| Ok(Value::Float(a / b)))?,
+            OpCode::Mod => self.binary(as_int, |a, b| {
+                if b == 0 {
+                    return Err(RuntimeErrorKind::DivisionByZero);
+                }
+                a.checked_rem(b)
+                    .map(Value::Int)
+                    .ok_or(RuntimeErrorKind::IntegerOverflow)
+            })?,
+            OpCode::True => self.value_stack.push(Value::Bool(true)),
+            OpCode::False => self.value_stack.push(Value::Bool(false)),
+            OpCode::FunctionCall | OpCode::FunctionCallLong => {
+                let args = self.pop_args(data[1])?;
+                self.call(data[0], args)?;
+            }
+            OpCode::TailCall | OpCode::TailCallLong => {
+                let args = self.pop_args(data[1])?;
+                self.tail_call(data[0], args);
+            }
+            OpCode::PushFunction | OpCode::PushFunctionLong => {
+                self.value_stack.push(Value::Fn(Rc::new(Closure {
+                    function: data[0],
+                    captures: Vec::new(),
+                })));
+            }
+            OpCode::MakeClosure | OpCode::MakeClosureLong => {
+                let captures = self.pop_args(data[1])?;
+                self.value_stack.push(Value::Fn(Rc::new(Closure {
+                    function: data[0],
+                    captures,
+                })));
+            }
+            OpCode::CallIndirect | OpCode::CallIndirectLong => {
+                let (next_func, locals) = self.pop_closure_call(data[0])?;
+                if self.natives[next_func].is_some() {
+                    let result = self.call_native(next_func, &locals)?;
+                    self.value_stack.push(result);
+                } else {
+                    self.call(next_func, locals)?;
                 }
-                OpCode::Advance => {
-                    let amount = data[0] as usize;
+            }
+            OpCode::TailCallIndirect | OpCode::TailCallIndirectLong => {
+                let (next_func, locals) = self.pop_closure_call(data[0])?;
+                if self.natives[next_func].is_some() {
+                    // natives have no frame to switch to, so return their result
+                    let result = self.call_native(next_func, &locals)?;
+                    self.value_stack.push(result);
+                    return self.return_from_call();
+                }
+                self.tail_call(next_func, locals);
+            }
+            OpCode::NativeCall | OpCode::NativeCallLong => {
+                let args = self.pop_args(data[1])?;
+                let result = self.call_native(data[0], &args)?;
+                self.value_stack.push(result);
+            }
+            OpCode::StackLoadLocalVar | OpCode::StackLoadLocalVarLong => {
+                let value = self.function_stack.last().unwrap().1[data[0]].clone();
+                self.value_stack.push(value);
+            }
+            OpCode::BindLocal => {
+                let value = self.value_stack_pop()?;
+                self.function_stack.last_mut().unwrap().1.push(value);
+            }
+            OpCode::PopLocal => {
+                self.function_stack.last_mut().unwrap().1.pop();
+            }
+            OpCode::Not => {
+                let a = self.value_stack_last_mut()?;
+                *a = Value::Bool(!as_bool(a)?);
+            }
+            // floats compare as IEEE 754 does, so NaN is unequal and unordered
+            OpCode::EqualI => self.binary(as_int, |a, b| Ok(Value::Bool(a == b)))?,
+            OpCode::EqualF => self.binary(as_float, |a, b| Ok(Value::Bool(a.0 == b.0)))?,
+            OpCode::EqualB => self.binary(as_bool, |a, b| Ok(Value::Bool(a == b)))?,
+            OpCode::EqualS => self.binary(as_string, |a, b| Ok(Value::Bool(a == b)))?,
+            OpCode::LessThanI => self.binary(as_int, |a, b| Ok(Value::Bool(a < b)))?,
+            OpCode::LessThanF => self.binary(as_float, |a, b| Ok(Value::Bool(a.0 < b.0)))?,
+            OpCode::GreaterThanI => self.binary(as_int, |a, b| Ok(Value::Bool(a > b)))?,
+            OpCode::GreaterThanF => self.binary(as_float, |a, b| Ok(Value::Bool(a.0 > b.0)))?,
+            OpCode::LessThanOrEqualI => self.binary(as_int, |a, b| Ok(Value::Bool(a <= b)))?,
+            OpCode::LessThanOrEqualF => {
+                self.binary(as_float, |a, b| Ok(Value::Bool(a.0 <= b.0)))?
+            }
+            OpCode::GreaterThanOrEqualI => self.binary(as_int, |a, b| Ok(Value::Bool(a >= b)))?,
+            OpCode::GreaterThanOrEqualF => {
+                self.binary(as_float, |a, b| Ok(Value::Bool(a.0 >= b.0)))?
+            }
+            OpCode::Advance | OpCode::AdvanceLong => {
+                let amount = data[0];
+                let current =
+                    self.program_data[self.function_stack.last().unwrap().0].get_pointer();
+                self.program_data[self.function_stack.last().unwrap().0]
+                    .set_pointer(current + amount);
+            }
+            OpCode::AdvanceIfFalse | OpCode::AdvanceIfFalseLong => {
+                let amount = data[0];
+                if !as_bool(&self.value_stack_pop()?)? {
                     let current =
                         self.program_data[self.function_stack.last().unwrap().0].get_pointer();
                     self.program_data[self.function_stack.last().unwrap().0]
                         .set_pointer(current + amount);
                 }
-                OpCode::AdvanceIfFalse => {
-                    let amount = data[0] as usize;
-                    let a = self.value_stack_pop();
-                    if !unsafe { a.b } {
-                        let current =
-                            self.program_data[self.function_stack.last().unwrap().0].get_pointer();
-                        self.program_data[self.function_stack.last().unwrap().0]
-                            .set_pointer(current + amount);
-                    }
-                }
-                OpCode::ConstructArray => {
-                    let size = data[0] as usize;
-                    let depth = data[1];
-                    if depth > 1 {
-                        self.array_depth_drop_stack.pop();
-                    }
-                    self.array_depth_drop_stack.push(depth);
-                    let mut arr = Vec::new();
-                    arr.resize(size, Value { i: 0 });
-                    for i in 0..size {
-                        arr[size - i - 1] = self.value_stack_pop();
-                    }
-                    arr.reverse();
-                    self.value_stack_push(&[Value {
-                        a: ManuallyDrop::new(ThinBox::new(arr)),
-                    }]);
-                }
-                OpCode::ConcatArr => {
-                    let mut b = self.value_stack_pop();
-                    let depth = self.array_depth_drop_stack.pop().unwrap();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        let a_arr: &mut Vec<Value> = a.a.deref_mut();
-                        a_arr.append(&mut *b.a);
-                        b.recursive_drop(depth);
-                    }
-                }
-                OpCode::ConcatStr => {
-                    let mut b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        let a_str: &mut String = a.s.deref_mut();
-                        a_str.push_str(b.s.as_str());
-                        ManuallyDrop::drop(&mut b.s);
-                    }
-                }
-                OpCode::LenArr => {
-                    let depth = self.array_depth_drop_stack.pop().unwrap();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        let len = a.a.len();
-                        a.clone().recursive_drop(depth);
-                        a.i = len as i64;
-                    }
-                }
-                OpCode::LenStr => {
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        let len = a.s.len();
-                        ManuallyDrop::drop(&mut a.s);
-                        a.i = len as i64;
-                    }
-                }
-                OpCode::Index => {
-                    let depth = self.array_depth_drop_stack.pop().unwrap();
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        let a_arr: &mut Vec<Value> = a.a.deref_mut();
-                        let val = a_arr[b.i as usize].clone();
-                        a.recursive_drop(depth);
-                        *a = val;
-                    }
-                }
-                OpCode::And => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.b && b.b;
-                    }
-                }
-                OpCode::Or => {
-                    let b = self.value_stack_pop();
-                    let a = self.value_stack_last_mut();
-                    unsafe {
-                        a.b = a.b || b.b;
-                    }
-                }
-                OpCode::NullCode => {
-                    panic!("NullCode");
-                }
             }
+            OpCode::ConstructArray | OpCode::ConstructArrayLong => {
+                let items = self.pop_args(data[0])?;
+                self.value_stack.push(Value::Arr(Rc::new(items)));
+            }
+            OpCode::ConcatArr => {
+                let b = self.value_stack_pop()?;
+                let Value::Arr(b) = b else {
+                    return Err(RuntimeErrorKind::TypeMismatch);
+                };
+                let len = array_len(self.value_stack_last_mut()?)?;
+                self.allocate(len.saturating_add(b.len()))?;
+                let Value::Arr(a) = self.value_stack_last_mut()? else {
+                    return Err(RuntimeErrorKind::TypeMismatch);
+                };
+                // appends in place when nothing else holds the left array
+                Rc::make_mut(a).extend(b.iter().cloned());
+            }
+            OpCode::ConcatStr => {
+                let b = as_string(&self.value_stack_pop()?)?;
+                let len = as_string(self.value_stack_last_mut()?)?.len();
+                self.allocate(len.saturating_add(b.len()))?;
+                let Value::Str(a) = self.value_stack_last_mut()? else {
+                    return Err(RuntimeErrorKind::TypeMismatch);
+                };
+                Rc::make_mut(a).push_str(&b);
+            }
+            OpCode::LenArr => {
+                let a = self.value_stack_last_mut()?;
+                let Value::Arr(items) = a else {
+                    return Err(RuntimeErrorKind::TypeMismatch);
+                };
+                *a = Value::Int(items.len() as i64);
+            }
+            OpCode::LenStr => {
+                let a = self.value_stack_last_mut()?;
+                *a = Value::Int(as_string(a)?.len() as i64);
+            }
+            OpCode::Index => {
+                let index = as_int(&self.value_stack_pop()?)?;
+                let a = self.value_stack_last_mut()?;
+                let Value::Arr(items) = a else {
+                    return Err(RuntimeErrorKind::TypeMismatch);
+                };
+                let val = match usize::try_from(index).ok().and_then(|i| items.get(i)) {
+                    Some(val) => val.clone(),
+                    None => {
+                        return Err(RuntimeErrorKind::IndexOutOfBounds {
+                            index,
+                            len: items.len(),
+                        });
+                    }
+                };
+                *a = val;
+            }
+            OpCode::ToFloat => {
+                let a = self.value_stack_last_mut()?;
+                *a = Value::Float(OrderedFloat(as_int(a)? as f64));
+            }
+            OpCode::ToInt => {
+                let a = self.value_stack_last_mut()?;
+                let f = as_float(a)?.0;
+                // 2^63 is the first float past the end of the int range
+                if f.is_nan() || f < i64::MIN as f64 || f >= i64::MAX as f64 {
+                    return Err(RuntimeErrorKind::IntegerOverflow);
+                }
+                *a = Value::Int(f as i64);
+            }
+            OpCode::ToString => {
+                let a = self.value_stack_last_mut()?;
+                let s = match a {
+                    Value::Int(i) => i.to_string(),
+                    Value::Float(f) => f.to_string(),
+                    Value::Bool(b) => b.to_string(),
+                    _ => return Err(RuntimeErrorKind::TypeMismatch),
+                };
+                *a = Value::Str(Rc::new(s));
+            }
+            OpCode::Range => {
+                let to = as_int(&self.value_stack_pop()?)?;
+                let a = self.value_stack_last_mut()?;
+                let from = as_int(a)?;
+                let len = usize::try_from(to.saturating_sub(from)).unwrap_or(0);
+                self.allocate(len)?;
+                let a = self.value_stack_last_mut()?;
+                *a = Value::Arr(Rc::new((from..to).map(Value::Int).collect()));
+            }
+            OpCode::Slice => {
+                let to = as_int(&self.value_stack_pop()?)?;
+                let from = as_int(&self.value_stack_pop()?)?;
+                let a = self.value_stack_last_mut()?;
+                let Value::Arr(items) = a else {
+                    return Err(RuntimeErrorKind::TypeMismatch);
+                };
+                let len = items.len();
+                let bound = |index: i64| {
+                    usize::try_from(index)
+                        .ok()
+                        .filter(|i| *i <= len)
+                        .ok_or(RuntimeErrorKind::IndexOutOfBounds { index, len })
+                };
+                let (start, end) = (bound(from)?, bound(to)?);
+                if start > end {
+                    return Err(RuntimeErrorKind::IndexOutOfBounds { index: from, len });
+                }
+                self.charge(end - start)?;
+                let Value::Arr(items) = self.value_stack_last_mut()? else {
+                    return Err(RuntimeErrorKind::TypeMismatch);
+                };
+                *items = Rc::new(items[start..end].to_vec());
+            }
+            OpCode::Reverse => {
+                let len = array_len(self.value_stack_last_mut()?)?;
+                self.charge(len)?;
+                let Value::Arr(items) = self.value_stack_last_mut()? else {
+                    return Err(RuntimeErrorKind::TypeMismatch);
+                };
+                // reverses in place when nothing else holds the array
+                Rc::make_mut(items).reverse();
+            }
+            OpCode::SortI => self.sort(as_int)?,
+            OpCode::SortF => self.sort(as_float)?,
+            OpCode::SortS => self.sort(as_string)?,
+            OpCode::Contains => {
+                let item = self.value_stack_pop()?;
+                let len = array_len(self.value_stack_last_mut()?)?;
+                self.charge(len)?;
+                let a = self.value_stack_last_mut()?;
+                let found = position(a, &item)?.is_some();
+                *a = Value::Bool(found);
+            }
+            OpCode::IndexOf => {
+                let item = self.value_stack_pop()?;
+                let len = array_len(self.value_stack_last_mut()?)?;
+                self.charge(len)?;
+                let a = self.value_stack_last_mut()?;
+                let index = position(a, &item)?.map_or(-1, |i| i as i64);
+                *a = Value::Int(index);
+            }
+            OpCode::Print | OpCode::Println => {
+                // strings print without the quotes they get inside arrays
+                let text = match self.value_stack.last() {
+                    Some(Value::Str(s)) => s.to_string(),
+                    Some(value) => BreakoutValue::from(value).to_string(),
+                    None => return Err(RuntimeErrorKind::StackUnderflow),
+                };
+                if op == OpCode::Println {
+                    writeln!(self.output, "{text}")
+                } else {
+                    write!(self.output, "{text}").and_then(|()| self.output.flush())
+                }
+                .map_err(|e| RuntimeErrorKind::Io(e.kind()))?;
+            }
+            OpCode::ReadLine => {
+                let line = self.read_line()?.map(|line| Value::Str(Rc::new(line)));
+                self.value_stack
+                    .push(Value::Arr(Rc::new(line.into_iter().collect())));
+            }
+            OpCode::ReadInt => {
+                let number = self.read_line()?.and_then(|line| line.trim().parse().ok());
+                self.value_stack.push(Value::Arr(Rc::new(
+                    number.map(Value::Int).into_iter().collect(),
+                )));
+            }
+            OpCode::Pop => {
+                self.value_stack_pop()?;
+            }
+            OpCode::And => self.binary(as_bool, |a, b| Ok(Value::Bool(a && b)))?,
+            OpCode::Or => self.binary(as_bool, |a, b| Ok(Value::Bool(a || b)))?,
+            OpCode::NullCode => {
+                let chunk = &self.program_data[self.function_stack.last().unwrap().0];
+                let byte = chunk.get_byte(chunk.get_pointer() - 1).unwrap();
+                return Err(RuntimeErrorKind::InvalidOpcode(byte));
+            }
+        }
+        Ok(None)
+    }
+    /// Replaces the top two values with `op` applied to them, after reading
+    /// both with `get`.
+    #[inline(always)]
+    fn binary<T>(
+        &mut self,
+        get: fn(&Value) -> Result<T, RuntimeErrorKind>,
+        op: impl FnOnce(T, T) -> Result<Value, RuntimeErrorKind>,
+    ) -> Result<(), RuntimeErrorKind> {
+        let b = get(&self.value_stack_pop()?)?;
+        let a = self.value_stack_last_mut()?;
+        *a = op(get(a)?, b)?;
+        Ok(())
+    }
+    /// Reads the next line of input without its line ending, or `None` at the
+    /// end of the input.
+    fn read_line(&mut self) -> Result<Option<String>, RuntimeErrorKind> {
+        let mut line = String::new();
+        let read = match &mut self.input {
+            Some(input) => input.read_line(&mut line),
+            None => io::stdin().read_line(&mut line),
+        }
+        .map_err(|e| RuntimeErrorKind::Io(e.kind()))?;
+        if read == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+    /// Sorts the array on top of the stack by the keys `get` reads from its
+    /// items. Equal items keep their order.
+    fn sort<T: Ord>(
+        &mut self,
+        get: fn(&Value) -> Result<T, RuntimeErrorKind>,
+    ) -> Result<(), RuntimeErrorKind> {
+        let len = array_len(self.value_stack_last_mut()?)?;
+        self.charge(len)?;
+        let Value::Arr(items) = self.value_stack_last_mut()? else {
+            return Err(RuntimeErrorKind::TypeMismatch);
+        };
+        let items = Rc::make_mut(items);
+        let mut keyed = items
+            .drain(..)
+            .map(|item| Ok((get(&item)?, item)))
+            .collect::<Result<Vec<_>, _>>()?;
+        keyed.sort_by(|(a, _), (b, _)| a.cmp(b));
+        items.extend(keyed.into_iter().map(|(_, item)| item));
+        Ok(())
+    }
+    /// Uses `elements` more fuel, for an instruction that builds or walks that
+    /// many elements.
+    fn charge(&mut self, elements: usize) -> Result<(), RuntimeErrorKind> {
+        self.fuel_used = self.fuel_used.saturating_add(elements as u64);
+        if self.fuel_used > self.budget {
+            return Err(RuntimeErrorKind::OutOfFuel {
+                budget: self.budget,
+            });
+        }
+        Ok(())
+    }
+    /// Checks that an array or string of length `len` may be built, and
+    /// charges for building it.
+    fn allocate(&mut self, len: usize) -> Result<(), RuntimeErrorKind> {
+        if len > self.max_array_len {
+            return Err(RuntimeErrorKind::TooLarge {
+                len,
+                max: self.max_array_len,
+            });
         }
+        self.charge(len)
     }
+    /// Pops `count` call arguments, first argument first.
     #[inline]
-    fn value_stack_push(&mut self, slice: &[Value]) {
-        self.value_stack.extend_from_slice(slice);
+    fn pop_args(&mut self, count: usize) -> Result<Vec<Value>, RuntimeErrorKind> {
+        let start = self
+            .value_stack
+            .len()
+            .checked_sub(count)
+            .ok_or(RuntimeErrorKind::StackUnderflow)?;
+        Ok(self.value_stack.split_off(start))
+    }
+    /// Pops `count` arguments and the closure below them, returning the
+    /// function to call and its locals: the captures, then the arguments.
+    fn pop_closure_call(&mut self, count: usize) -> Result<(usize, Vec<Value>), RuntimeErrorKind> {
+        let mut args = self.pop_args(count)?;
+        let Value::Fn(closure) = self.value_stack_pop()? else {
+            return Err(RuntimeErrorKind::TypeMismatch);
+        };
+        let next_func = closure.function;
+        // function values are only type checked by the compiler
+        let arity = closure.captures.len() + count;
+        if self.function_signatures[next_func].params.len() != arity {
+            return Err(RuntimeErrorKind::InvalidCall {
+                function: next_func,
+                args: count,
+            });
+        }
+        let mut locals = closure.captures.clone();
+        locals.append(&mut args);
+        Ok((next_func, locals))
     }
     #[inline]
-    fn value_stack_pop(&mut self) -> Value {
-        self.value_stack.pop().unwrap()
+    fn call(&mut self, function: usize, locals: Vec<Value>) -> Result<(), RuntimeErrorKind> {
+        if self.function_stack.len() >= self.max_call_depth
+            || self.value_stack.len() > self.max_stack_size
+        {
+            return Err(RuntimeErrorKind::StackOverflow {
+                calls: self.function_stack.len(),
+                values: self.value_stack.len(),
+            });
+        }
+        self.position_stack
+            .push(self.program_data[self.function_stack.last().unwrap().0].get_pointer());
+        self.function_stack.push((function, locals));
+        self.program_data[function].set_pointer(0);
+        Ok(())
     }
+    /// Returns the value on top of the stack from the current call, or from
+    /// the run once main returns.
     #[inline]
-    fn value_stack_last_mut(&mut self) -> &mut Value {
-        self.value_stack.last_mut().unwrap()
+    fn return_from_call(&mut self) -> Result<Option<Value>, RuntimeErrorKind> {
+        self.function_stack.pop();
+        if self.function_stack.is_empty() {
+            return self.value_stack_pop().map(Some);
+        }
+        let position = self
+            .position_stack
+            .pop()
+            .ok_or(RuntimeErrorKind::StackUnderflow)?;
+        self.program_data[self.function_stack.last().unwrap().0].set_pointer(position);
+        Ok(None)
+    }
+    fn call_native(&self, function: usize, args: &[Value]) -> Result<Value, RuntimeErrorKind> {
+        let native = self.natives[function]
+            .as_ref()
+            .ok_or(RuntimeErrorKind::InvalidCall {
+                function,
+                args: args.len(),
+            })?;
+        let signature = &self.function_signatures[function];
+        let failed = |message| RuntimeErrorKind::NativeFailed {
+            name: signature.name.clone(),
+            message,
+        };
+        // the compiler trusts the declared return type, so the host must keep to it
+        let value = native(args).map_err(failed)?;
+        if !self.has_type(&value, &signature.ret) {
+            return Err(failed(format!(
+                "Returned {}, which is not {}",
+                BreakoutValue::from(&value),
+                signature.ret
+            )));
+        }
+        Ok(value)
+    }
+    /// Whether `value` can be used where `t` is expected. A function value
+    /// matches the parameters its closure has not captured.
+    pub(crate) fn has_type(&self, value: &Value, t: &Type) -> bool {
+        match (value, t) {
+            (Value::Int(_), Type::Int)
+            | (Value::Float(_), Type::Float)
+            | (Value::Bool(_), Type::Bool)
+            | (Value::Str(_), Type::String) => true,
+            (Value::Arr(items), Type::Array(item_type)) => {
+                items.iter().all(|item| self.has_type(item, item_type))
+            }
+            (Value::Fn(closure), Type::Function(params, ret)) => self
+                .function_signatures
+                .get(closure.function)
+                .is_some_and(|f| {
+                    f.params.get(closure.captures.len()..) == Some(params.as_slice())
+                        && f.ret == **ret
+                }),
+            _ => false,
+        }
+    }
+    /// Replaces the current frame, dropping its locals.
+    #[inline]
+    fn tail_call(&mut self, function: usize, locals: Vec<Value>) {
+        *self.function_stack.last_mut().unwrap() = (function, locals);
+        self.program_data[function].set_pointer(0);
+    }
+    #[inline]
+    fn value_stack_pop(&mut self) -> Result<Value, RuntimeErrorKind> {
+        self.value_stack
+            .pop()
+            .ok_or(RuntimeErrorKind::StackUnderflow)
+    }
+    #[inline]
+    fn value_stack_last_mut(&mut self) -> Result<&mut Value, RuntimeErrorKind> {
+        self.value_stack
+            .last_mut()
+            .ok_or(RuntimeErrorKind::StackUnderflow)
+    }
+}
+
+// The compiler type checks every instruction's operands, so these only fail
+// on bytecode that was not compiled from valid source.
+#[inline(always)]
+fn as_int(value: &Value) -> Result<i64, RuntimeErrorKind> {
+    match value {
+        Value::Int(i) => Ok(*i),
+        _ => Err(RuntimeErrorKind::TypeMismatch),
+    }
+}
+#[inline(always)]
+fn as_float(value: &Value) -> Result<OrderedFloat<f64>, RuntimeErrorKind> {
+    match value {
+        Value::Float(f) => Ok(*f),
+        _ => Err(RuntimeErrorKind::TypeMismatch),
+    }
+}
+#[inline(always)]
+fn as_bool(value: &Value) -> Result<bool, RuntimeErrorKind> {
+    match value {
+        Value::Bool(b) => Ok(*b),
+        _ => Err(RuntimeErrorKind::TypeMismatch),
+    }
+}
+/// Length of the array `a`.
+fn array_len(a: &Value) -> Result<usize, RuntimeErrorKind> {
+    match a {
+        Value::Arr(items) => Ok(items.len()),
+        _ => Err(RuntimeErrorKind::TypeMismatch),
+    }
+}
+/// Index of the first item of the array `a` equal to `item`, compared like
+/// the typed equality opcodes compare them.
+fn position(a: &Value, item: &Value) -> Result<Option<usize>, RuntimeErrorKind> {
+    let Value::Arr(items) = a else {
+        return Err(RuntimeErrorKind::TypeMismatch);
+    };
+    let equal = |other: &Value| match (other, item) {
+        (Value::Int(a), Value::Int(b)) => Ok(a == b),
+        // like ==, so NaN is never found
+        (Value::Float(a), Value::Float(b)) => Ok(a.0 == b.0),
+        (Value::Bool(a), Value::Bool(b)) => Ok(a == b),
+        (Value::Str(a), Value::Str(b)) => Ok(a == b),
+        _ => Err(RuntimeErrorKind::TypeMismatch),
+    };
+    for (i, other) in items.iter().enumerate() {
+        if equal(other)? {
+            return Ok(Some(i));
+        }
+    }
+    Ok(None)
+}
+#[inline(always)]
+fn as_string(value: &Value) -> Result<Rc<String>, RuntimeErrorKind> {
+    match value {
+        Value::Str(s) => Ok(s.clone()),
+        _ => Err(RuntimeErrorKind::TypeMismatch),
     }
 }
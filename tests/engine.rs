@@ -0,0 +1,378 @@
+//! The embedding API: loading definitions, calling them and evaluating
+//! expressions.
+
+use bytecodeinterpreter::chunk::Chunk;
+use bytecodeinterpreter::value::{FunctionSignature, Type, Value};
+use bytecodeinterpreter::vm::{RuntimeErrorKind, VM};
+use bytecodeinterpreter::{BreakoutValue, Engine, EngineError};
+use std::rc::Rc;
+
+const RULES: &str = "int discount := total : int member : bool | && member > total 100 => 15 | member => 5 | => 0
+[int] scale := xs : [int] by : int i : int | == i len xs => [] | => ++ [* by @ xs i] scale xs by + i 1
+string label := name : string => ++ \"item-\" name
+int apply := f : (int -> int) x : int => f x
+int double := x : int => * 2 x
+(int -> int) pick := double";
+
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine.load_source(RULES).unwrap();
+    engine
+}
+
+#[test]
+fn call_with_arguments() {
+    let mut engine = engine();
+    let result = engine.call(
+        "discount",
+        &[BreakoutValue::Int(150), BreakoutValue::Bool(true)],
+    );
+    assert_eq!(result.unwrap(), BreakoutValue::Int(15));
+    let result = engine.call(
+        "scale",
+        &[
+            BreakoutValue::Array(vec![BreakoutValue::Int(1), BreakoutValue::Int(2)]),
+            BreakoutValue::Int(3),
+            BreakoutValue::Int(0),
+        ],
+    );
+    assert_eq!(
+        result.unwrap(),
+        BreakoutValue::Array(vec![BreakoutValue::Int(3), BreakoutValue::Int(6)])
+    );
+    let result = engine.call("label", &[BreakoutValue::String("a".to_string())]);
+    assert_eq!(result.unwrap(), BreakoutValue::String("item-a".to_string()));
+}
+
+#[test]
+fn call_with_a_function_argument() {
+    let mut engine = engine();
+    let double = engine.call("pick", &[]).unwrap();
+    assert!(matches!(double, BreakoutValue::Function(_)));
+    let result = engine.call("apply", &[double, BreakoutValue::Int(21)]);
+    assert_eq!(result.unwrap(), BreakoutValue::Int(42));
+    // a lambda returned from eval stays loaded so it can be called later
+    let triple = engine.eval("x : int => * 3 x").unwrap();
+    engine.eval("+ 1 2").unwrap();
+    let result = engine.call("apply", &[triple, BreakoutValue::Int(5)]);
+    assert_eq!(result.unwrap(), BreakoutValue::Int(15));
+}
+
+#[test]
+fn call_checks_arguments() {
+    let mut engine = engine();
+    assert!(matches!(
+        engine.call("missing", &[]),
+        Err(EngineError::UnknownFunction(_))
+    ));
+    assert!(matches!(
+        engine.call("discount", &[BreakoutValue::Int(1)]),
+        Err(EngineError::InvalidArguments(_))
+    ));
+    assert!(matches!(
+        engine.call("discount", &[BreakoutValue::Int(1), BreakoutValue::Int(1)]),
+        Err(EngineError::InvalidArguments(_))
+    ));
+    assert!(matches!(
+        engine.call("apply", &[BreakoutValue::Int(1), BreakoutValue::Int(1)]),
+        Err(EngineError::InvalidArguments(_))
+    ));
+    // the VM itself refuses functions that do not exist
+    let error = engine
+        .vm_mut()
+        .call_function(10_000, Vec::new(), 1000)
+        .unwrap_err();
+    assert_eq!(
+        error.kind,
+        RuntimeErrorKind::InvalidCall {
+            function: 10_000,
+            args: 0
+        }
+    );
+}
+
+#[test]
+fn eval_expressions() {
+    let mut engine = engine();
+    assert_eq!(
+        engine.eval("+ discount 50 true 1").unwrap(),
+        BreakoutValue::Int(6)
+    );
+    assert_eq!(
+        engine.eval("scale [1 2 3] 2 0").unwrap().to_string(),
+        "[2 4 6]"
+    );
+    assert_eq!(
+        engine.eval("apply x : int => + x 1 ; 1").unwrap(),
+        BreakoutValue::Int(2)
+    );
+    assert!(matches!(engine.eval("+ 1"), Err(EngineError::Compile(_))));
+    assert!(matches!(engine.eval("1 2"), Err(EngineError::Compile(_))));
+    // failed and finished evals leave the loaded functions usable
+    assert_eq!(
+        engine.call("double", &[BreakoutValue::Int(4)]).unwrap(),
+        BreakoutValue::Int(8)
+    );
+}
+
+#[test]
+fn later_loads_can_call_earlier_ones() {
+    let mut engine = engine();
+    engine
+        .load_source("int quad := x : int => double double x")
+        .unwrap();
+    assert_eq!(
+        engine.call("quad", &[BreakoutValue::Int(3)]).unwrap(),
+        BreakoutValue::Int(12)
+    );
+}
+
+#[test]
+fn failed_loads_change_nothing() {
+    let mut engine = engine();
+    assert!(matches!(
+        engine.load_source("int ok := 1\nint bad := + 1 true"),
+        Err(EngineError::Compile(_))
+    ));
+    assert!(matches!(
+        engine.call("ok", &[]),
+        Err(EngineError::UnknownFunction(_))
+    ));
+    engine.load_source("int ok := 2").unwrap();
+    assert_eq!(engine.call("ok", &[]).unwrap(), BreakoutValue::Int(2));
+}
+
+#[test]
+fn runtime_errors_and_fuel() {
+    let mut engine = engine();
+    engine
+        .load_source("int spin := n : int => spin + n 1\nint div := x : int => / 10 x")
+        .unwrap();
+    match engine.call("div", &[BreakoutValue::Int(0)]) {
+        Err(EngineError::Runtime(e)) => assert_eq!(e.kind, RuntimeErrorKind::DivisionByZero),
+        other => panic!("expected division by zero, got {other:?}"),
+    }
+    engine.set_fuel(1000);
+    match engine.call("spin", &[BreakoutValue::Int(0)]) {
+        Err(EngineError::Runtime(e)) => {
+            assert_eq!(e.kind, RuntimeErrorKind::OutOfFuel { budget: 1000 })
+        }
+        other => panic!("expected to run out of fuel, got {other:?}"),
+    }
+    // the engine calls functions by name and never sets a main to run
+    let e = engine.vm_mut().run().unwrap_err();
+    assert_eq!(e.kind, RuntimeErrorKind::NoMain);
+}
+
+#[test]
+fn natives_must_match_their_signatures() {
+    let signature = FunctionSignature::new("one".to_string(), Vec::new(), Type::Int);
+    let mut vm = VM::new();
+    vm.give_native(Rc::new(|_: &[Value]| Ok(Value::Int(1))));
+    vm.update_function_signatures(std::slice::from_ref(&signature));
+    assert_eq!(
+        vm.verify().unwrap_err().message,
+        "A native function is registered for one, which is not native"
+    );
+    let mut vm = VM::new();
+    vm.give_data(Chunk::new(Vec::new()));
+    vm.update_function_signatures(&[FunctionSignature {
+        native: true,
+        ..signature
+    }]);
+    assert_eq!(
+        vm.verify().unwrap_err().message,
+        "No native function registered for one"
+    );
+}
+
+#[test]
+fn runtime_errors_have_a_trace() {
+    let mut engine = engine();
+    engine
+        .load_source("int inner := x : int => / 10 x\nint outer := x : int => + 1 inner x")
+        .unwrap();
+    let Err(EngineError::Runtime(error)) = engine.call("outer", &[BreakoutValue::Int(0)]) else {
+        panic!("expected a runtime error");
+    };
+    assert_eq!(
+        error.to_string(),
+        "Runtime error:\n\tDivision by zero\n\tat inner (<source>:1:25)\n\tat outer (<source>:2:29)"
+    );
+}
+
+#[test]
+fn stack_overflow() {
+    let mut engine = engine();
+    engine
+        .load_source("int deep := n : int => + 1 deep + n 1")
+        .unwrap();
+    engine.vm_mut().set_max_call_depth(50);
+    let error = match engine.call("deep", &[BreakoutValue::Int(0)]) {
+        Err(EngineError::Runtime(e)) => e,
+        other => panic!("expected a stack overflow, got {other:?}"),
+    };
+    assert!(matches!(
+        error.kind,
+        RuntimeErrorKind::StackOverflow { calls: 50, .. }
+    ));
+    assert_eq!(error.trace.len(), 50);
+    assert!(
+        error
+            .trace
+            .iter()
+            .all(|frame| frame.name.as_deref() == Some("deep"))
+    );
+    // only the innermost and outermost frames are printed
+    let message = error.to_string();
+    assert!(message.contains("... 30 more frames"), "{message}");
+    assert_eq!(message.matches("at deep").count(), 20);
+
+    engine.vm_mut().set_max_call_depth(1000);
+    engine.vm_mut().set_max_stack_size(10);
+    match engine.call("deep", &[BreakoutValue::Int(0)]) {
+        Err(EngineError::Runtime(e)) => match e.kind {
+            RuntimeErrorKind::StackOverflow { calls, values } => {
+                assert_eq!(values, 11);
+                assert!(calls < 1000);
+            }
+            other => panic!("expected a stack overflow, got {other}"),
+        },
+        other => panic!("expected a stack overflow, got {other:?}"),
+    }
+}
+
+#[test]
+fn natives() {
+    let mut engine = engine();
+    engine.register_native("sqrt", vec![Type::Float], Type::Float, |args| match args {
+        [BreakoutValue::Float(x)] if *x >= 0.0 => Ok(BreakoutValue::Float(x.sqrt())),
+        _ => Err("negative argument".to_string()),
+    });
+    engine.register_native("bonus", vec![Type::Int], Type::Int, |args| match args {
+        [BreakoutValue::Int(x)] => Ok(BreakoutValue::Int(x + 10)),
+        _ => unreachable!(),
+    });
+    engine
+        .load_source(
+            "float root := x : float => sqrt x
+int inner := x : int => + 1 bonus x
+int viaValue := x : int => apply bonus x
+(int -> int) bonusFn := bonus",
+        )
+        .unwrap();
+    assert_eq!(
+        engine.call("root", &[BreakoutValue::Float(16.0)]).unwrap(),
+        BreakoutValue::Float(4.0)
+    );
+    assert_eq!(
+        engine.call("inner", &[BreakoutValue::Int(1)]).unwrap(),
+        BreakoutValue::Int(12)
+    );
+    // apply calls its function argument in tail position
+    assert_eq!(
+        engine.call("viaValue", &[BreakoutValue::Int(1)]).unwrap(),
+        BreakoutValue::Int(11)
+    );
+    let bonus = engine.call("bonusFn", &[]).unwrap();
+    assert_eq!(
+        engine
+            .call("apply", &[bonus, BreakoutValue::Int(2)])
+            .unwrap(),
+        BreakoutValue::Int(12)
+    );
+    assert_eq!(
+        engine.call("bonus", &[BreakoutValue::Int(0)]).unwrap(),
+        BreakoutValue::Int(10)
+    );
+    assert_eq!(engine.eval("+ 1 bonus 5").unwrap(), BreakoutValue::Int(16));
+    assert!(matches!(
+        engine.load_source("float bad := sqrt 2"),
+        Err(EngineError::Compile(_))
+    ));
+    match engine.call("root", &[BreakoutValue::Float(-1.0)]) {
+        Err(EngineError::Runtime(e)) => assert_eq!(
+            e.kind,
+            RuntimeErrorKind::NativeFailed {
+                name: "sqrt".to_string(),
+                message: "negative argument".to_string()
+            }
+        ),
+        other => panic!("expected sqrt to fail, got {other:?}"),
+    }
+    // a native must return its declared type
+    engine.register_native("liar", Vec::new(), Type::Int, |_| {
+        Ok(BreakoutValue::String("one".to_string()))
+    });
+    engine.load_source("int trust := + 1 liar").unwrap();
+    match engine.call("trust", &[]) {
+        Err(EngineError::Runtime(e)) => assert_eq!(
+            e.kind,
+            RuntimeErrorKind::NativeFailed {
+                name: "liar".to_string(),
+                message: "Returned \"one\", which is not int".to_string()
+            }
+        ),
+        other => panic!("expected liar to fail, got {other:?}"),
+    }
+}
+
+#[test]
+fn closures_keep_their_captures() {
+    let mut engine = engine();
+    engine
+        .load_source("(int -> int) adder := n : int => x : int => + n x")
+        .unwrap();
+    let add5 = engine.call("adder", &[BreakoutValue::Int(5)]).unwrap();
+    let result = engine.call("apply", &[add5.clone(), BreakoutValue::Int(2)]);
+    assert_eq!(result.unwrap(), BreakoutValue::Int(7));
+    // captures also survive an eval that returns a closure from a lambda
+    let add3 = engine.eval("punch n := 3 x : int => + n x").unwrap();
+    let result = engine.call("apply", &[add3.clone(), BreakoutValue::Int(2)]);
+    assert_eq!(result.unwrap(), BreakoutValue::Int(5));
+    assert_ne!(add5, add3);
+    assert_eq!(add5, add5.clone());
+}
+
+#[test]
+#[cfg_attr(miri, ignore)] // Miri isolates the test from the file system
+fn load_file() {
+    let mut engine = Engine::new();
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/src/array_test.bo");
+    engine.load_file(path).unwrap();
+    assert_eq!(
+        engine.call("main", &[]).unwrap(),
+        BreakoutValue::Bool(false)
+    );
+    assert!(matches!(
+        engine.load_file("does/not/exist.bo"),
+        Err(EngineError::Io(_))
+    ));
+}
+
+#[test]
+#[cfg_attr(miri, ignore)] // Miri isolates the test from the file system
+fn compile_errors_point_into_included_files() {
+    let dir = std::env::temp_dir().join(format!("breakout-include-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let lib = dir.join("lib.bo");
+    let main = dir.join("main.bo");
+    std::fs::write(&lib, "int ok := 1\nint broken := + 1 true\n").unwrap();
+    std::fs::write(
+        &main,
+        format!("include {}\nint main := ok\n", lib.display()),
+    )
+    .unwrap();
+    let error = Engine::new().load_file(main.to_str().unwrap());
+    std::fs::remove_dir_all(&dir).unwrap();
+    let Err(EngineError::Compile(error)) = error else {
+        panic!("expected a compile error, got {error:?}");
+    };
+    assert_eq!(
+        error.to_string(),
+        format!(
+            "Error at {}:2:15:\n\tType mismatch\n\tint broken := + 1 true\n\t              ^",
+            lib.display()
+        )
+    );
+}
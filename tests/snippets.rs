@@ -0,0 +1,492 @@
+//! Compiles and runs Breakout snippets in process, so the VM's handling of
+//! strings, arrays and closures can also be checked under Miri.
+
+use bytecodeinterpreter::vm::RuntimeErrorKind;
+use bytecodeinterpreter::{BreakoutValue, Engine, EngineError};
+use std::cell::RefCell;
+use std::io::{Cursor, Write};
+use std::rc::Rc;
+
+/// Loads `source` and runs its main, returning its result.
+fn execute(source: &str) -> Result<BreakoutValue, EngineError> {
+    let mut engine = Engine::new();
+    engine.load_source(source)?;
+    engine.call("main", &[])
+}
+
+/// Runs `source` and returns the result of main as it would be printed.
+fn run(source: &str) -> String {
+    match execute(source) {
+        Ok(result) => result.to_string(),
+        Err(e) => panic!("{e}"),
+    }
+}
+
+/// Runs `source`, expecting it to fail at runtime with `kind`.
+fn run_error(source: &str) -> RuntimeErrorKind {
+    match execute(source) {
+        Ok(result) => panic!("expected a runtime error, got {result}"),
+        Err(EngineError::Runtime(e)) => e.kind,
+        Err(e) => panic!("expected a runtime error, got {e}"),
+    }
+}
+
+#[test]
+fn results_are_owned_values() {
+    let result = execute("[[string]] main := [[\"a\"] [\"b\" \"c\"]]").unwrap();
+    assert_eq!(
+        result,
+        BreakoutValue::Array(vec![
+            BreakoutValue::Array(vec![BreakoutValue::String("a".to_string())]),
+            BreakoutValue::Array(vec![
+                BreakoutValue::String("b".to_string()),
+                BreakoutValue::String("c".to_string()),
+            ]),
+        ])
+    );
+    assert_eq!(
+        execute("float main := 1.5").unwrap(),
+        BreakoutValue::Float(1.5)
+    );
+}
+
+#[test]
+fn scalars() {
+    assert_eq!(run("int main := + 1 * 2 3"), "7");
+    assert_eq!(run("float main := / 1.0 4.0"), "0.25");
+    assert_eq!(run("bool main := && true ! false"), "true");
+}
+
+#[test]
+fn not_and_not_equal() {
+    assert_eq!(
+        run("[bool] main := [!= 1 2 != 3 3 != 1.5 2.5 != 0.5 0.5]"),
+        "[true false true false]"
+    );
+    assert_eq!(
+        run("[bool] main := [!= true false != false false != \"a\" \"b\" != \"a\" \"a\"]"),
+        "[true false true false]"
+    );
+    assert_eq!(
+        run("[bool] main := [! true ! false ! ! true]"),
+        "[false true true]"
+    );
+    for source in [
+        "bool main := != [1] [1]",
+        "bool main := != 1 1.0",
+        "bool main := ! 1",
+        "bool main := ! \"true\"",
+    ] {
+        assert!(matches!(execute(source), Err(EngineError::Compile(_))));
+    }
+}
+
+#[test]
+fn long_operands() {
+    // more than 256 constants
+    let numbers: Vec<String> = (1..=300).map(|i| i.to_string()).collect();
+    let source = format!("int main := @ [{}] 299", numbers.join(" "));
+    assert_eq!(run(&source), "300");
+
+    // more than 256 locals, and as many arguments
+    let params: Vec<String> = (0..300).map(|i| format!("a{i} : int")).collect();
+    let source = format!(
+        "int last := {} => - a299 a0\nint main := last {}",
+        params.join(" "),
+        numbers.join(" ")
+    );
+    assert_eq!(run(&source), "299");
+
+    // a cond branch longer than 255 bytes, which needs long jumps
+    let source = format!(
+        "int pick := b : bool => cond b len [{0}] + 1 len [{0}]\n[int] main := [pick true pick false]",
+        numbers.join(" ")
+    );
+    assert_eq!(run(&source), "[300 301]");
+}
+
+#[test]
+fn float_comparisons_follow_ieee() {
+    let source = "float nan := / 0.0 0.0
+[bool] main := [== nan nan != nan nan < nan 1.0 >= nan 1.0 contains [nan] nan == 0.0 - 0.0 0.0]";
+    assert_eq!(run(source), "[false true false false false true]");
+}
+
+#[test]
+fn deeply_nested_expressions() {
+    let source = format!("int main := {}0", "+ 1 ".repeat(150));
+    assert_eq!(run(&source), "150");
+    let source = format!("int main := len {}[[0]]", "++ [[1]] ".repeat(150));
+    assert_eq!(run(&source), "151");
+    // the compiler refuses nesting it cannot compile without exhausting the
+    // stack, with the deepest expression still allowed at 200 levels
+    let source = format!("int main := {}0", "+ 1 ".repeat(199));
+    assert_eq!(run(&source), "199");
+    let source = format!(
+        "int inc := x : int => + x 1\nint main := {}0",
+        "inc ".repeat(199)
+    );
+    assert_eq!(run(&source), "199");
+    let source = format!("int main := {}0", "cond false 0 ".repeat(199));
+    assert_eq!(run(&source), "0");
+    let source = format!("int main := {}0", "punch a := 1 ".repeat(199));
+    assert_eq!(run(&source), "0");
+    for nested in ["+ 1 ", "inc ", "cond false 0 ", "punch a := 1 ", "abs "] {
+        let source = format!(
+            "int inc := x : int => + x 1\nint main := {}0",
+            nested.repeat(200)
+        );
+        match execute(&source) {
+            Err(EngineError::Compile(e)) => assert_eq!(e.message, "Expression nested too deeply"),
+            result => panic!("expected a compile error, got {result:?}"),
+        }
+    }
+}
+
+#[test]
+fn array_literals_keep_their_order() {
+    assert_eq!(run("[int] main := [1 2 3]"), "[1 2 3]");
+    assert_eq!(run("[[int]] main := [[1 2] [] [3]]"), "[[1 2] [] [3]]");
+}
+
+#[test]
+fn nested_arrays() {
+    let source = "bool h := == len [] 0
+[[int]] f := [[1 2 3 4 5] [6 7 8 9 10]]
+bool g := x : [[[[int]]]] => == 0 len x
+bool main := && && && g [[[[2]]]] g [[[[]]]] == 2 len f h";
+    assert_eq!(run(source), "false");
+    let source = "[[int]] f := [[1 2 3 4 5] [6 7 8 9 10]]
+int main := + len f len @ f 1";
+    assert_eq!(run(source), "7");
+}
+
+#[test]
+fn array_locals_can_be_used_twice() {
+    let source = "int f := a : [[int]] => + + len a len @ a 0 @ @ a 1 0
+int main := f [[1 2] [3]]";
+    assert_eq!(run(source), "7");
+}
+
+#[test]
+fn concat() {
+    assert_eq!(run("[int] main := ++ [1] [2 3]"), "[1 2 3]");
+    assert_eq!(
+        run("[[int]] main := ++ [[1] [2]] [[3 4]]"),
+        "[[1] [2] [3 4]]"
+    );
+    assert_eq!(run("string main := ++ \"ab\" \"cd\""), "\"abcd\"");
+}
+
+#[test]
+fn concat_leaves_constants_alone() {
+    let source = "string f := s : string => ++ s \"!\"
+string main := ++ f \"a\" f \"a\"";
+    assert_eq!(run(source), "\"a!a!\"");
+    let source = "[int] f := a : [int] => ++ a [0]
+[int] main := ++ f [1] f [1]";
+    assert_eq!(run(source), "[1 0 1 0]");
+}
+
+#[test]
+fn index() {
+    assert_eq!(run("int main := @ [10 20 30] 1"), "20");
+    assert_eq!(run("int main := @ @ [[1 2] [3 4]] 1 0"), "3");
+    assert_eq!(run("string main := @ [\"a\" \"b\"] 1"), "\"b\"");
+    assert_eq!(
+        run_error("int main := @ [1 2] 2"),
+        RuntimeErrorKind::IndexOutOfBounds { index: 2, len: 2 }
+    );
+}
+
+#[test]
+fn len() {
+    assert_eq!(run("int main := len [1 2 3]"), "3");
+    assert_eq!(run("int main := len \"hello\""), "5");
+    assert_eq!(run("int main := len [[1] [2] []]"), "3");
+}
+
+#[test]
+fn strings_as_arguments() {
+    let source = "int f := s : string t : string => + len s len t
+bool g := s : string => == s \"ab\"
+bool main := && == f \"ab\" \"cde\" 5 g \"ab\"";
+    assert_eq!(run(source), "true");
+}
+
+#[test]
+fn punch_bindings_of_heap_values() {
+    let source = "int main := punch a := [[1 2] [3]] punch s := \"xyz\" + len a len s";
+    assert_eq!(run(source), "5");
+}
+
+#[test]
+fn closures_capture_heap_values() {
+    let source =
+        "string greet := name : string => punch g := s : string => ++ ++ s \"-\" name ; g \"hi\"
+string main := greet \"bob\"";
+    assert_eq!(run(source), "\"hi-bob\"");
+    let source = "int total := xs : [int] => punch f := i : int => @ xs i ; + f 0 f 1
+int main := total [4 5]";
+    assert_eq!(run(source), "9");
+    let source = "int main := punch a := [1] punch b := 5 punch n := 2 punch g := x : int => punch y := + x n + y len a ; g b";
+    assert_eq!(run(source), "8");
+}
+
+#[test]
+#[cfg_attr(miri, ignore)] // Millions of instructions take too long under Miri
+fn long_closure_chains_drop() {
+    let source =
+        "int g := f : (int -> int) n : int | == n 0 => 0 | => punch h := x : int => f x ; g h - n 1
+int main := g x : int => x ; 3000000";
+    assert_eq!(run(source), "0");
+}
+
+#[test]
+fn recursion_over_arrays() {
+    let source = "[int] build := n : int acc : [int] | == n 0 => acc | => build - n 1 ++ [n] acc
+int sum := a : [int] i : int acc : int | == i len a => acc | => sum a + i 1 + acc @ a i
+int main := sum build 50 [] 0 0";
+    assert_eq!(run(source), "1275");
+}
+
+#[test]
+fn numeric_prelude() {
+    assert_eq!(run("int main := + abs - 0 7 max 2 min 3 4"), "10");
+    assert_eq!(run("float main := fmax fabs - 0.0 2.5 fmin 1.0 2.0"), "2.5");
+    assert_eq!(run("float main := sqrt 16.0"), "4");
+    // Miri makes pow slightly imprecise on purpose
+    match execute("float main := pow 2.0 3.0").unwrap() {
+        BreakoutValue::Float(x) => assert!((x - 8.0).abs() < 1e-9, "{x}"),
+        other => panic!("expected a float, got {other}"),
+    }
+    assert_eq!(
+        run("[float] main := [floor 2.5 ceil 2.5 round 2.5 round - 0.0 2.5]"),
+        "[2 3 3 -3]"
+    );
+    // definitions shadow prelude functions of the same name
+    assert_eq!(
+        run("int max := a : int b : int => a\nint main := max 1 2"),
+        "1"
+    );
+}
+
+#[test]
+fn int_float_conversions() {
+    assert_eq!(run("float main := / toFloat 7 2.0"), "3.5");
+    assert_eq!(run("[int] main := [toInt 2.9 toInt - 0.0 2.9]"), "[2 -2]");
+    assert_eq!(
+        run_error("int main := toInt / 0.0 0.0"),
+        RuntimeErrorKind::IntegerOverflow
+    );
+    assert_eq!(
+        run_error("int main := toInt 1e19"),
+        RuntimeErrorKind::IntegerOverflow
+    );
+    assert!(matches!(
+        execute("int main := + 1 toFloat 2"),
+        Err(EngineError::Compile(_))
+    ));
+}
+
+#[test]
+fn string_prelude() {
+    assert_eq!(
+        run("string main := substring \"breakout\" 0 5"),
+        "\"break\""
+    );
+    assert_eq!(run("int main := byteAt \"A\" 0"), "65");
+    assert_eq!(run("[string] main := chars \"añb\""), "[\"a\" \"ñ\" \"b\"]");
+    assert_eq!(
+        run("[string] main := split \"a,b,,c\" \",\""),
+        "[\"a\" \"b\" \"\" \"c\"]"
+    );
+    assert_eq!(
+        run("string main := join split \"a,b,c\" \",\" \"-\""),
+        "\"a-b-c\""
+    );
+    assert_eq!(
+        run("string main := ++ upper trim \"  ab \" lower \"CD\""),
+        "\"ABcd\""
+    );
+    assert!(matches!(
+        run_error("string main := substring \"añb\" 0 2"),
+        RuntimeErrorKind::NativeFailed { .. }
+    ));
+    assert!(matches!(
+        run_error("int main := byteAt \"ab\" 2"),
+        RuntimeErrorKind::NativeFailed { .. }
+    ));
+}
+
+#[test]
+fn string_conversions() {
+    assert_eq!(
+        run("string main := ++ ++ toString 12 toString 2.5 toString true"),
+        "\"122.5true\""
+    );
+    assert_eq!(run("[int] main := parseInt \" 42 \""), "[42]");
+    assert_eq!(run("[int] main := parseInt \"4x\""), "[]");
+    assert_eq!(run("[float] main := parseFloat \"0.5\""), "[0.5]");
+    assert!(matches!(
+        execute("string main := toString \"a\""),
+        Err(EngineError::Compile(_))
+    ));
+}
+
+#[test]
+fn array_functions() {
+    assert_eq!(run("[int] main := range 2 5"), "[2 3 4]");
+    assert_eq!(run("[int] main := range 5 2"), "[]");
+    assert_eq!(run("[int] main := slice range 0 10 3 6"), "[3 4 5]");
+    assert_eq!(run("[[int]] main := slice [[1] [2]] 2 2"), "[]");
+    assert_eq!(
+        run("[string] main := reverse [\"a\" \"b\" \"c\"]"),
+        "[\"c\" \"b\" \"a\"]"
+    );
+    assert_eq!(run("[int] main := sort [3 1 2 1]"), "[1 1 2 3]");
+    assert_eq!(
+        run("[float] main := sort [2.5 - 0.0 0.5 1.0]"),
+        "[-0.5 1 2.5]"
+    );
+    assert_eq!(
+        run("[string] main := sort [\"b\" \"ab\" \"a\"]"),
+        "[\"a\" \"ab\" \"b\"]"
+    );
+    assert_eq!(
+        run("bool main := && contains [1 2] 2 ! contains [1 2] 3"),
+        "true"
+    );
+    assert_eq!(
+        run("[int] main := [indexOf [\"a\" \"b\" \"b\"] \"b\" indexOf [1.5] 2.0]"),
+        "[1 -1]"
+    );
+    assert_eq!(
+        run_error("[int] main := slice [1 2 3] 2 4"),
+        RuntimeErrorKind::IndexOutOfBounds { index: 4, len: 3 }
+    );
+    assert_eq!(
+        run_error("[int] main := slice [1 2 3] 2 1"),
+        RuntimeErrorKind::IndexOutOfBounds { index: 2, len: 3 }
+    );
+    assert!(matches!(
+        execute("[bool] main := sort [true false]"),
+        Err(EngineError::Compile(_))
+    ));
+    assert!(matches!(
+        execute("bool main := contains [1 2] 1.0"),
+        Err(EngineError::Compile(_))
+    ));
+}
+
+#[test]
+fn array_functions_leave_shared_arrays_alone() {
+    let source = "[[int]] main := punch xs := [3 1 2] [xs sort xs reverse xs]";
+    assert_eq!(run(source), "[[3 1 2] [1 2 3] [2 1 3]]");
+}
+
+#[test]
+fn definitions_shadow_builtins() {
+    let source = "[int] reverse := xs : [int] => xs
+[int] main := reverse [1 2 3]";
+    assert_eq!(run(source), "[1 2 3]");
+    let source = "int twice := sort : int print : int => + sort print
+[int] main := [twice 1 2 @ sort [3 1] 0]";
+    assert_eq!(run(source), "[3 1]");
+    assert_eq!(run("int main := punch toInt := 5 + toInt 2"), "7");
+}
+
+#[test]
+fn array_sizes_are_limited() {
+    assert_eq!(
+        run_error("[int] main := range 0 100000000000"),
+        RuntimeErrorKind::TooLarge {
+            len: 100_000_000_000,
+            max: 10_000_000
+        }
+    );
+    let source = "string double := s : string => double ++ s s
+string main := double \"ab\"";
+    assert!(matches!(
+        run_error(source),
+        RuntimeErrorKind::TooLarge {
+            max: 10_000_000,
+            ..
+        }
+    ));
+    // building and walking arrays costs fuel per element
+    let mut engine = Engine::new();
+    engine
+        .load_source("[int] main := sort range 0 1000")
+        .unwrap();
+    engine.set_fuel(1500);
+    assert!(matches!(
+        engine.call("main", &[]),
+        Err(EngineError::Runtime(e)) if e.kind == RuntimeErrorKind::OutOfFuel { budget: 1500 }
+    ));
+    engine.set_fuel(2010);
+    assert!(engine.call("main", &[]).is_ok());
+    assert_eq!(engine.vm_mut().instruction_count(), 5);
+    assert_eq!(engine.vm_mut().fuel_used(), 2005);
+}
+
+/// Collects what a program prints, so the test can read it after the run.
+#[derive(Clone, Default)]
+struct Output(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs `source` with `input` as its input, returning the result of main
+/// and everything it printed.
+fn run_io(source: &str, input: &str) -> (String, String) {
+    let output = Output::default();
+    let mut engine = Engine::new();
+    engine.vm_mut().set_output(Box::new(output.clone()));
+    engine
+        .vm_mut()
+        .set_input(Box::new(Cursor::new(input.to_string())));
+    engine.load_source(source).unwrap();
+    let result = engine.call("main", &[]).unwrap().to_string();
+    let printed = String::from_utf8(output.0.borrow().clone()).unwrap();
+    (result, printed)
+}
+
+#[test]
+fn print_in_sequence() {
+    let source = "int main := kick print \"a\" kick println [\"b\" \"c\"] println 1";
+    assert_eq!(
+        run_io(source, ""),
+        ("1".to_string(), "a[\"b\" \"c\"]\n1\n".to_string())
+    );
+    // kick works in any position, and keeps tail calls in its last part
+    let source = "int count := n : int | == n 0 => 0 | => kick print n count - n 1
+int main := + 1 count 1000";
+    let (result, printed) = run_io(source, "");
+    assert_eq!(result, "1");
+    assert!(printed.starts_with("1000999998997"));
+    let mut engine = Engine::new();
+    engine.vm_mut().set_output(Box::new(Output::default()));
+    engine.vm_mut().set_max_call_depth(10);
+    engine.load_source(source).unwrap();
+    assert_eq!(engine.call("main", &[]).unwrap(), BreakoutValue::Int(1));
+}
+
+#[test]
+fn read_lines_and_ints() {
+    let source = "[[string]] main := [readLine readLine readLine]";
+    assert_eq!(run_io(source, "one\r\ntwo").0, "[[\"one\"] [\"two\"] []]");
+    let source = "int sum := acc : int => punch n := readInt cond == 0 len n acc sum + acc @ n 0
+int main := sum 0";
+    assert_eq!(run_io(source, "1\n 2 \n39\nend\n5\n").0, "42");
+    assert!(matches!(
+        execute("int main := readLine"),
+        Err(EngineError::Compile(_))
+    ));
+}